@@ -1,159 +1,1110 @@
-use std::io::Write;
 /*
  * Copyright 2024 Oxide Computer Company
  */
 
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread::JoinHandle;
-use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::bootimage;
+use crate::compress::{self, Compression};
 use crate::maybe_unlink;
-use anyhow::{bail, Result};
-use helios_build_utils::metadata::Metadata;
+use crate::sparse::{self, Region, SparseReader};
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/**
+ * The name of the manifest, appended to the image/ directory as the last entry
+ * in the archive, which contains the SHA-256 hash of every other file in the
+ * directory.  The format is that of sha256sum(1).
+ */
+pub const SHA256SUMS: &str = "SHA256SUMS";
+
+/**
+ * The permissions and ownership of an entry in the archive.
+ */
+#[derive(Clone, Debug)]
+pub struct Attrs {
+    pub mode: u32,
+    pub user: String,
+    pub uid: u64,
+    pub group: String,
+    pub gid: u64,
+}
+
+impl Attrs {
+    fn root() -> Attrs {
+        Attrs {
+            mode: 0,
+            user: "root".into(),
+            uid: 0,
+            group: "root".into(),
+            gid: 0,
+        }
+    }
+
+    /**
+     * Unless otherwise requested, files are read-only, and all entries are
+     * owned by root.
+     */
+    pub fn file() -> Attrs {
+        Attrs::root().mode(0o444)
+    }
+
+    pub fn directory() -> Attrs {
+        Attrs::root().mode(0o755)
+    }
+
+    pub fn symlink() -> Attrs {
+        Attrs::root().mode(0o777)
+    }
+
+    pub fn mode(mut self, mode: u32) -> Attrs {
+        self.mode = mode;
+        self
+    }
+}
 
 enum Act {
-    File(String, PathBuf),
-    FileWithData(String, Vec<u8>),
+    File(String, PathBuf, Attrs),
+    SparseFile(String, PathBuf, Attrs),
+    FileWithData(String, Vec<u8>, Attrs),
+    Directory(String, Attrs),
+    Symlink(String, String, Attrs),
     Complete,
 }
 
 /**
- * Create a tar file with the gzip compressor running in another thread.  Files
- * are pushed from the main thread into a channel, where the worker thread adds
+ * Create a tar file with the compressor running in another thread.  Files are
+ * pushed from the main thread into a channel, where the worker thread adds
  * files to the archive as directed.  The result, success or error, is made
- * available to the user when they join the worker thread.
+ * available to the user when they join the worker thread.  If the worker
+ * fails before then, the error is also reported by the next attempt to add a
+ * file, so that the caller can stop early.
+ *
+ * The archive is written to a temporary file in the same directory, which is
+ * renamed into place only once it is complete.  If the archive cannot be
+ * completed, or is dropped without calling finish(), the temporary file is
+ * removed and any existing file at the destination is left untouched.
+ *
+ * Entries are named relative to the image/ directory, and may include
+ * directory components; e.g., "roms/gimlet.rom".  Any parent directory that
+ * has not already been added is created, with default attributes, before the
+ * entry itself.
+ *
+ * Every entry in the archive is stamped with the same modification time,
+ * provided by the caller, and the compressed stream does not include a
+ * timestamp.  Given the same inputs, added in the same order, the archive
+ * produced will be byte-for-byte identical.
  */
 pub struct Archive {
     tx: mpsc::Sender<Act>,
-    hdl: JoinHandle<Result<()>>,
+    hdl: JoinHandle<Result<String>>,
+    error: Arc<Mutex<Option<String>>>,
 }
 
-impl Archive {
-    pub fn new(p: &Path, m: Metadata) -> Result<Archive> {
-        let path = p.to_path_buf();
+/**
+ * Wrap a reader so that the data can be hashed as it is read.
+ */
+struct HashReader<R: Read> {
+    r: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let sz = self.r.read(buf)?;
+        self.hasher.update(&buf[..sz]);
+        Ok(sz)
+    }
+}
+
+/**
+ * Create the header for an entry in the archive.  For files, only the size is
+ * taken from the file itself; the ownership and timestamps of the file on disk
+ * are not interesting, and would make the archive vary between otherwise
+ * identical builds.
+ */
+fn header(
+    t: tar::EntryType,
+    name: &str,
+    size: u64,
+    mtime: u64,
+    a: &Attrs,
+) -> Result<tar::Header> {
+    fill_header(tar::Header::new_ustar(), t, name, size, mtime, a)
+}
+
+fn fill_header(
+    mut h: tar::Header,
+    t: tar::EntryType,
+    name: &str,
+    size: u64,
+    mtime: u64,
+    a: &Attrs,
+) -> Result<tar::Header> {
+    h.set_entry_type(t);
+    h.set_mode(a.mode);
+    h.set_username(&a.user)?;
+    h.set_uid(a.uid);
+    h.set_groupname(&a.group)?;
+    h.set_gid(a.gid);
+    h.set_path(name)?;
+    h.set_mtime(mtime);
+    h.set_size(size);
+
+    Ok(h)
+}
+
+/**
+ * Produce the header for a GNU sparse entry, which lists the regions of the
+ * file that contain data; everything else is a hole, full of zeroes.  The
+ * header has room for only a few regions, so the rest are stored in extension
+ * blocks that must be written to the archive directly after the header.
+ * Returns the header, and the extension blocks.
+ */
+fn sparse_header(
+    name: &str,
+    regions: &[Region],
+    len: u64,
+    mtime: u64,
+    a: &Attrs,
+) -> Result<(tar::Header, Vec<u8>)> {
+    let stored = regions.iter().map(|r| r.length).sum();
+    let mut h = fill_header(
+        tar::Header::new_gnu(),
+        tar::EntryType::GNUSparse,
+        name,
+        stored,
+        mtime,
+        a,
+    )?;
+
+    /*
+     * If the file ends in a hole, the list must end with an empty region at
+     * the end of the file so that readers know how far to extend it.
+     */
+    let mut regions = regions.to_vec();
+    if regions.last().map(|r| r.offset + r.length) != Some(len) {
+        regions.push(Region { offset: len, length: 0 });
+    }
+
+    let gnu = h.as_gnu_mut().unwrap();
+    gnu.set_real_size(len);
+    let (first, rest) = regions.split_at(regions.len().min(gnu.sparse.len()));
+    for (sh, r) in gnu.sparse.iter_mut().zip(first.iter()) {
+        sh.set_offset(r.offset);
+        sh.set_length(r.length);
+    }
+    gnu.set_is_extended(!rest.is_empty());
+
+    let mut ext = Vec::new();
+    let per = tar::GnuExtSparseHeader::new().sparse.len();
+    let mut chunks = rest.chunks(per).peekable();
+    while let Some(c) = chunks.next() {
+        let mut eh = tar::GnuExtSparseHeader::new();
+        for (sh, r) in eh.sparse.iter_mut().zip(c.iter()) {
+            sh.set_offset(r.offset);
+            sh.set_length(r.length);
+        }
+        eh.set_is_extended(chunks.peek().is_some());
+        ext.extend_from_slice(eh.as_bytes());
+    }
+
+    Ok((h, ext))
+}
+
+/**
+ * Check that an entry name is a relative path, without any empty, "." or ".."
+ * components.
+ */
+fn check_name(n: &str) -> Result<()> {
+    if n.is_empty()
+        || n.split('/').any(|c| c.is_empty() || c == "." || c == "..")
+    {
+        bail!(
+            "{n:?} must be a relative path, without empty, \".\" or \"..\" \
+            components"
+        );
+    }
+
+    Ok(())
+}
 
-        maybe_unlink(&path)?;
+struct Worker {
+    tar: tar::Builder<compress::Encoder<std::fs::File>>,
+    mtime: u64,
+    names: BTreeSet<String>,
+    sums: String,
+}
+
+impl Worker {
+    fn create(
+        p: &Path,
+        m: &impl Serialize,
+        mtime: u64,
+        compression: Compression,
+    ) -> Result<Worker> {
         let f = std::fs::OpenOptions::new()
             .create(true)
             .truncate(true)
             .write(true)
-            .open(&path)?;
-        let gzw = flate2::write::GzEncoder::new(f, flate2::Compression::best());
-        let mut tar = tar::Builder::new(gzw);
-        let mtime =
-            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            .open(p)?;
+        let tar = tar::Builder::new(compression.encoder(f)?);
+
+        let mut w = Worker {
+            tar,
+            mtime,
+            names: Default::default(),
+            sums: String::new(),
+        };
 
         /*
-         * Append the metadata file first in the archive.
+         * Append the metadata file first in the archive.  We serialise it
+         * ourselves, rather than using append_to_tar(), so that the keys
+         * appear in a stable order and the entry carries the same timestamp
+         * as the rest of the archive.
          */
-        m.append_to_tar(&mut tar)?;
+        let mut md = serde_json::to_vec(&serde_json::to_value(m)?)?;
+        md.push(b'\n');
+        let h = header(
+            tar::EntryType::Regular,
+            "oxide.json",
+            md.len() as u64,
+            mtime,
+            &Attrs::file(),
+        )?;
+        w.append(h, md.as_slice())?;
 
         /*
          * Append the image/ directory under which all of our data files will be
          * included:
          */
-        {
-            let mut h = tar::Header::new_ustar();
+        w.act(Act::Directory("image".to_string(), Attrs::directory()))?;
 
-            h.set_entry_type(tar::EntryType::Directory);
-            h.set_username("root")?;
-            h.set_uid(0);
-            h.set_groupname("root")?;
-            h.set_gid(0);
-            h.set_path("image")?;
-            h.set_mtime(mtime);
-            h.set_mode(0o755);
-            h.set_size(0);
-            h.set_cksum();
+        Ok(w)
+    }
 
-            tar.append(&h, std::io::empty())?;
+    fn run(mut self, rx: &mpsc::Receiver<Act>) -> Result<String> {
+        /*
+         * Each file is hashed as it is added to the archive, so that we can
+         * produce the manifest at the end without reading anything twice.
+         */
+        loop {
+            match rx.recv() {
+                Ok(Act::Complete) => break,
+                Ok(act) => self.act(act)?,
+                Err(_) => bail!("archive was abandoned before completion"),
+            }
         }
 
+        let sums = std::mem::take(&mut self.sums);
+        self.act(Act::FileWithData(
+            format!("image/{SHA256SUMS}"),
+            sums.as_bytes().to_vec(),
+            Attrs::file(),
+        ))?;
+
+        let enc = self.tar.into_inner()?;
+        let mut f = enc.finish()?;
+        f.flush()?;
+        f.sync_all()?;
+        Ok(sums)
+    }
+
+    fn append(&mut self, mut h: tar::Header, data: impl Read) -> Result<()> {
+        let name = h.path()?.to_str().unwrap().to_string();
+
+        /*
+         * Make sure each parent directory appears in the archive before the
+         * entry itself:
+         */
+        let mut parent = name.as_str();
+        let mut missing = Vec::new();
+        while let Some((p, _)) = parent.rsplit_once('/') {
+            if self.names.contains(p) {
+                break;
+            }
+            missing.push(p.to_string());
+            parent = p;
+        }
+        for dir in missing.into_iter().rev() {
+            let mut dh = header(
+                tar::EntryType::Directory,
+                &dir,
+                0,
+                self.mtime,
+                &Attrs::directory(),
+            )?;
+            dh.set_cksum();
+            self.tar.append(&dh, std::io::empty())?;
+            self.names.insert(dir);
+        }
+
+        if !self.names.insert(name.clone()) {
+            bail!("{name:?} has already been added to the archive");
+        }
+
+        h.set_cksum();
+        self.tar.append(&h, data)?;
+        Ok(())
+    }
+
+    fn act(&mut self, act: Act) -> Result<()> {
+        let mtime = self.mtime;
+
+        match act {
+            Act::File(name, path, a) => {
+                let f = std::fs::OpenOptions::new()
+                    .create(false)
+                    .read(true)
+                    .open(&path)?;
+
+                let t = tar::EntryType::Regular;
+                let h = header(t, &name, f.metadata()?.len(), mtime, &a)?;
+
+                let mut hr = HashReader { r: f, hasher: Sha256::new() };
+                self.append(h, &mut hr)?;
+
+                self.sums += &sums_line(&hr.hasher.finalize(), &name);
+            }
+            Act::SparseFile(name, path, a) => {
+                let mut f = std::fs::OpenOptions::new()
+                    .create(false)
+                    .read(true)
+                    .open(&path)?;
+
+                let Some(regions) = sparse::data_regions(&mut f)? else {
+                    /*
+                     * The file has no holes, so store it in the usual way.
+                     */
+                    return self.act(Act::File(name, path, a));
+                };
+
+                let len = f.metadata()?.len();
+                let (h, ext) = sparse_header(&name, &regions, len, mtime, &a)?;
+
+                let mut sr = SparseReader::new(f, &regions, len);
+                self.append(h, ext.as_slice().chain(&mut sr))?;
+
+                self.sums += &sums_line(&sr.finish(), &name);
+            }
+            Act::FileWithData(name, data, a) => {
+                let t = tar::EntryType::Regular;
+                let h = header(t, &name, data.len() as u64, mtime, &a)?;
+
+                self.append(h, data.as_slice())?;
+
+                self.sums += &sums_line(&Sha256::digest(&data), &name);
+            }
+            Act::Directory(name, a) => {
+                let t = tar::EntryType::Directory;
+                let h = header(t, &name, 0, mtime, &a)?;
+
+                self.append(h, std::io::empty())?;
+            }
+            Act::Symlink(name, target, a) => {
+                let t = tar::EntryType::Symlink;
+                let mut h = header(t, &name, 0, mtime, &a)?;
+                h.set_link_name(&target)?;
+
+                self.append(h, std::io::empty())?;
+            }
+            Act::Complete => unreachable!(),
+        }
+
+        Ok(())
+    }
+}
+
+impl Archive {
+    /**
+     * Begin writing an archive.  The metadata, which is written to oxide.json
+     * at the start of the archive, is usually a Metadata object from
+     * helios-build-utils, but may be any map that includes at least the "v"
+     * and "t" keys.
+     */
+    pub fn new(
+        p: &Path,
+        m: impl Serialize,
+        mtime: u64,
+        compression: Compression,
+    ) -> Result<Archive> {
+        let path = p.to_path_buf();
+        let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+            bail!("invalid archive path {path:?}");
+        };
+        let tmp = path
+            .with_file_name(format!(".{file_name}.{}.tmp", std::process::id()));
+
+        let w = match Worker::create(&tmp, &m, mtime, compression) {
+            Ok(w) => w,
+            Err(e) => {
+                maybe_unlink(&tmp).ok();
+                return Err(e);
+            }
+        };
+
         let (tx, rx) = mpsc::channel();
+        let error = Arc::new(Mutex::new(None));
 
-        let hdl = std::thread::spawn(move || -> Result<()> {
-            loop {
-                match rx.recv().unwrap() {
-                    Act::File(name, path) => {
-                        let f = std::fs::OpenOptions::new()
-                            .create(false)
-                            .read(true)
-                            .open(&path)?;
-
-                        let mut h = tar::Header::new_ustar();
-
-                        h.set_entry_type(tar::EntryType::Regular);
-                        h.set_metadata(&f.metadata()?);
-                        h.set_mode(0o444);
-                        h.set_username("root")?;
-                        h.set_uid(0);
-                        h.set_groupname("root")?;
-                        h.set_uid(0);
-                        h.set_path(&name)?;
-                        h.set_mtime(mtime);
-                        h.set_cksum();
-
-                        tar.append(&h, f)?;
-                    }
-                    Act::FileWithData(name, data) => {
-                        let mut h = tar::Header::new_ustar();
-
-                        let mtime = SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs();
-
-                        h.set_entry_type(tar::EntryType::Regular);
-                        h.set_mode(0o444);
-                        h.set_username("root")?;
-                        h.set_uid(0);
-                        h.set_groupname("root")?;
-                        h.set_uid(0);
-                        h.set_path(&name)?;
-                        h.set_mtime(mtime);
-                        h.set_size(data.len().try_into().unwrap());
-                        h.set_cksum();
-
-                        tar.append(&h, data.as_slice())?;
-                    }
-                    Act::Complete => break,
+        let werror = Arc::clone(&error);
+        let hdl = std::thread::spawn(move || -> Result<String> {
+            /*
+             * The receiver is kept open until any error has been recorded, so
+             * that a sender which finds the channel closed can report it.
+             */
+            match w.run(&rx).and_then(|sums| {
+                std::fs::rename(&tmp, &path)?;
+                Ok(sums)
+            }) {
+                Ok(sums) => Ok(sums),
+                Err(e) => {
+                    *werror.lock().unwrap() = Some(e.to_string());
+                    maybe_unlink(&tmp).ok();
+                    Err(e)
                 }
             }
-
-            let gzw = tar.into_inner()?;
-            let mut f = gzw.finish()?;
-            f.flush()?;
-            Ok(())
         });
 
-        Ok(Archive { tx, hdl })
+        Ok(Archive { tx, hdl, error })
+    }
+
+    /**
+     * Pass an action to the worker thread, first checking that the worker has
+     * not already failed.
+     */
+    fn send(&self, act: Act) -> Result<()> {
+        self.check()?;
+        if self.tx.send(act).is_err() {
+            /*
+             * The worker has exited.  If it recorded a reason, report that.
+             */
+            self.check()?;
+            bail!("archive worker thread exited unexpectedly");
+        }
+        Ok(())
+    }
+
+    fn check(&self) -> Result<()> {
+        if let Some(e) = self.error.lock().unwrap().as_deref() {
+            bail!("could not write archive: {e}");
+        }
+        Ok(())
     }
 
     pub fn add_file(&self, p: &Path, n: &str) -> Result<()> {
+        self.add_file_with_attrs(p, n, Attrs::file())
+    }
+
+    pub fn add_file_with_attrs(
+        &self,
+        p: &Path,
+        n: &str,
+        a: Attrs,
+    ) -> Result<()> {
         if !p.is_file() {
             bail!("{p:?} is not a file");
         }
+        check_name(n)?;
+
+        self.send(Act::File(format!("image/{n}"), p.to_path_buf(), a))
+    }
 
-        if n.contains("/") {
-            bail!("{n:?} must be a bare file name, not directory components");
+    /**
+     * Add a file that may contain large regions of zeroes.  Holes in the file,
+     * or blocks that contain only zeroes, are stored as a GNU sparse entry and
+     * are neither read nor compressed.
+     */
+    pub fn add_sparse_file(&self, p: &Path, n: &str) -> Result<()> {
+        if !p.is_file() {
+            bail!("{p:?} is not a file");
         }
+        check_name(n)?;
 
-        self.tx.send(Act::File(format!("image/{n}"), p.to_path_buf()))?;
-        Ok(())
+        self.send(Act::SparseFile(
+            format!("image/{n}"),
+            p.to_path_buf(),
+            Attrs::file(),
+        ))
     }
 
     pub fn add_file_with_data(&self, data: Vec<u8>, n: &str) -> Result<()> {
-        if n.contains("/") {
-            bail!("{n:?} must be a bare file name, not directory components");
+        self.add_data_with_attrs(data, n, Attrs::file())
+    }
+
+    pub fn add_data_with_attrs(
+        &self,
+        data: Vec<u8>,
+        n: &str,
+        a: Attrs,
+    ) -> Result<()> {
+        check_name(n)?;
+
+        self.send(Act::FileWithData(format!("image/{n}"), data, a))
+    }
+
+    /**
+     * Add a directory.  This is only required if the directory needs
+     * attributes other than the default, or must appear even if nothing is
+     * added to it, and it must be added before any entries within it.
+     */
+    pub fn add_directory(&self, n: &str, a: Attrs) -> Result<()> {
+        check_name(n)?;
+
+        self.send(Act::Directory(format!("image/{n}"), a))
+    }
+
+    pub fn add_symlink(&self, n: &str, target: &str) -> Result<()> {
+        check_name(n)?;
+        if target.is_empty() {
+            bail!("symbolic link {n:?} needs a target");
         }
 
-        self.tx.send(Act::FileWithData(format!("image/{n}"), data))?;
-        Ok(())
+        self.send(Act::Symlink(
+            format!("image/{n}"),
+            target.to_string(),
+            Attrs::symlink(),
+        ))
+    }
+
+    /**
+     * Complete the archive.  Returns the contents of the SHA256SUMS manifest
+     * that was included, so that the caller may store a copy elsewhere.
+     */
+    pub fn finish(self) -> Result<String> {
+        /*
+         * If the worker has already failed, the send will fail too; either
+         * way, the worker's own result is the most useful thing to report.
+         */
+        self.tx.send(Act::Complete).ok();
+        self.hdl
+            .join()
+            .map_err(|_| anyhow!("archive worker thread panicked"))?
+    }
+}
+
+fn sums_line(hash: &[u8], name: &str) -> String {
+    let name = name.strip_prefix("image/").unwrap_or(name);
+    format!("{}  {name}\n", hex(hash))
+}
+
+/**
+ * Parse a SHA256SUMS manifest into a map from file name to hash.
+ */
+pub fn parse_sums(sums: &str) -> Result<BTreeMap<String, String>> {
+    let mut out = BTreeMap::new();
+
+    for l in sums.lines() {
+        let Some((hash, name)) = l.split_once("  ") else {
+            bail!("invalid manifest line {l:?}");
+        };
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("invalid hash in manifest line {l:?}");
+        }
+        if out.insert(name.to_string(), hash.to_string()).is_some() {
+            bail!("duplicate file {name:?} in manifest");
+        }
+    }
+
+    Ok(out)
+}
+
+/**
+ * Render a sequence of bytes, such as a hash, as lower-case hexadecimal.
+ */
+pub fn hex(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/**
+ * Compute the SHA-256 hash of a file, as hexadecimal.
+ */
+pub fn file_sha256(p: &Path) -> Result<String> {
+    let mut f = std::fs::File::open(p)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut f, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+pub struct Entry {
+    /**
+     * The name of the file, relative to the image/ directory.
+     */
+    pub name: String,
+    pub mode: u32,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/**
+ * The contents of an existing archive, as written by Archive, with any of the
+ * supported compression formats.  The text files we include for diagnostic
+ * purposes are loaded into memory; for all other files, we just record the
+ * size and hash while streaming through them.
+ */
+pub struct Contents {
+    pub metadata: BTreeMap<String, String>,
+    pub entries: Vec<Entry>,
+    /**
+     * Symbolic links, relative to the image/ directory, and their targets.
+     */
+    pub links: BTreeMap<String, String>,
+    pub infos: BTreeMap<String, String>,
+    pub boot_image: Option<bootimage::Summary>,
+    /**
+     * Problems found while reading the archive that do not prevent us from
+     * listing the rest of it; e.g., a boot image with an invalid header.
+     */
+    pub problems: Vec<String>,
+}
+
+impl Contents {
+    pub fn read(p: &Path) -> Result<Contents> {
+        let f = std::fs::File::open(p)
+            .map_err(|e| anyhow!("opening {p:?}: {e}"))?;
+        let mut tar = tar::Archive::new(compress::decoder(f)?);
+
+        let mut metadata = None;
+        let mut entries = Vec::new();
+        let mut links = BTreeMap::new();
+        let mut infos = BTreeMap::new();
+        let mut boot_image = None;
+        let mut problems = Vec::new();
+        let mut buf = vec![0u8; 1024 * 1024];
+
+        for ent in tar.entries()? {
+            let mut ent = ent?;
+            let path = ent.path()?.to_str().map(str::to_string);
+            let Some(path) = path else {
+                bail!("non-UTF-8 path in archive {p:?}");
+            };
+
+            match ent.header().entry_type() {
+                tar::EntryType::Directory => continue,
+                tar::EntryType::Symlink => {
+                    let target = ent.link_name()?.ok_or_else(|| {
+                        anyhow!("symbolic link {path:?} has no target")
+                    })?;
+                    let name = path.strip_prefix("image/").unwrap_or(&path);
+                    links.insert(
+                        name.to_string(),
+                        target.to_string_lossy().to_string(),
+                    );
+                    continue;
+                }
+                tar::EntryType::Regular | tar::EntryType::GNUSparse => (),
+                other => bail!("unexpected {other:?} entry {path:?}"),
+            }
+
+            if path == "oxide.json" {
+                let mut s = String::new();
+                ent.read_to_string(&mut s)?;
+                let m: serde_json::Map<String, serde_json::Value> =
+                    serde_json::from_str(&s)?;
+                metadata = Some(
+                    m.into_iter()
+                        .map(|(k, v)| match v {
+                            serde_json::Value::String(s) => (k, s),
+                            other => (k, other.to_string()),
+                        })
+                        .collect::<BTreeMap<_, _>>(),
+                );
+                continue;
+            }
+
+            let Some(name) = path.strip_prefix("image/") else {
+                bail!("unexpected file {path:?} outside image/ directory");
+            };
+            let name = name.to_string();
+            let mode = ent.header().mode()?;
+
+            let mut hasher = Sha256::new();
+            let mut text = if name.ends_with(".txt") || name == SHA256SUMS {
+                Some(Vec::new())
+            } else {
+                None
+            };
+            let mut checker = if name == "zfs.img" {
+                Some(bootimage::Checker::default())
+            } else {
+                None
+            };
+
+            let mut size = 0;
+            loop {
+                let sz = ent.read(&mut buf)?;
+                if sz == 0 {
+                    break;
+                }
+                size += sz as u64;
+
+                hasher.update(&buf[..sz]);
+                if let Some(text) = text.as_mut() {
+                    text.extend_from_slice(&buf[..sz]);
+                }
+                if let Some(checker) = checker.as_mut() {
+                    checker.write_all(&buf[..sz])?;
+                }
+            }
+
+            if let Some(text) = text {
+                infos.insert(
+                    name.clone(),
+                    String::from_utf8_lossy(&text).to_string(),
+                );
+            }
+            if let Some(checker) = checker {
+                match checker.finish() {
+                    Ok(bi) => boot_image = Some(bi),
+                    Err(e) => problems.push(e.to_string()),
+                }
+            }
+
+            entries.push(Entry {
+                name,
+                mode,
+                size,
+                sha256: hex(&hasher.finalize()),
+            });
+        }
+
+        let Some(metadata) = metadata else {
+            bail!("archive {p:?} does not contain oxide.json");
+        };
+
+        Ok(Contents { metadata, entries, links, infos, boot_image, problems })
+    }
+}
+
+/**
+ * Unpack an archive into a directory.  Sparse entries are written out as
+ * sparse files, seeking over the holes rather than filling them with zeroes.
+ */
+pub fn extract(p: &Path, dir: &Path) -> Result<()> {
+    let f = std::fs::File::open(p)?;
+    let mut a = tar::Archive::new(compress::decoder(f)?);
+    a.unpack(dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{os_archive, TempDir};
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Instant;
+
+    #[test]
+    fn reproducible() {
+        let dir = TempDir::new("archive-repro");
+        let input = dir.join("input");
+
+        let build = |name: &str, c: Compression| {
+            /*
+             * Rewrite the input and the metadata each time, so that neither the
+             * timestamp on the file nor the iteration order of the map can leak
+             * into the archive.
+             */
+            std::fs::write(&input, "some data\n".repeat(1000)).unwrap();
+            let md: HashMap<String, String> = (0..20)
+                .map(|i| (format!("key{i}"), format!("value{i}")))
+                .chain([("v".into(), "1".into()), ("t".into(), "os".into())])
+                .collect();
+
+            let p = dir.join(name);
+            let a = Archive::new(&p, md, 1710882076, c).unwrap();
+            a.add_file(&input, "input").unwrap();
+            a.add_file_with_data(b"abc\n".to_vec(), "roms/abc.txt").unwrap();
+            a.finish().unwrap();
+            std::fs::read(&p).unwrap()
+        };
+
+        for spec in ["gzip", "zstd", "xz"] {
+            for threads in [1, 2] {
+                let c = Compression {
+                    threads,
+                    ..Compression::parse(spec).unwrap()
+                };
+                let a = build("a.tar", c);
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                assert!(
+                    a == build("b.tar", c),
+                    "{spec} with {threads} threads"
+                );
+            }
+        }
     }
 
-    pub fn finish(self) -> Result<()> {
-        self.tx.send(Act::Complete)?;
-        self.hdl.join().unwrap()
+    #[test]
+    fn bad_boot_image() {
+        let dir = TempDir::new("archive-bootimg");
+
+        let p = dir.join("os.tar.gz");
+        let a = os_archive(&p);
+        a.add_file_with_data(vec![1; 8192], "zfs.img").unwrap();
+        a.add_file_with_data(b"x\n".to_vec(), "pkg-list.txt").unwrap();
+        a.finish().unwrap();
+
+        /*
+         * A broken boot image must not stop us listing the rest of the archive.
+         */
+        let c = Contents::read(&p).unwrap();
+        assert!(c.boot_image.is_none());
+        assert_eq!(c.problems, vec!["boot image has bad magic 0x1010101"]);
+        assert_eq!(c.entries.len(), 3);
+        assert_eq!(c.infos["pkg-list.txt"], "x\n");
+    }
+
+    #[test]
+    fn layout() {
+        let dir = TempDir::new("archive-layout");
+        let script = dir.join("script");
+        std::fs::write(&script, "#!/bin/sh\nexit 0\n").unwrap();
+
+        let p = dir.join("os.tar.gz");
+        let a = os_archive(&p);
+        a.add_file_with_data(b"rom\n".to_vec(), "gimlet.rom").unwrap();
+        a.add_directory("roms", Attrs::directory().mode(0o750)).unwrap();
+        a.add_symlink("roms/gimlet.rom", "../gimlet.rom").unwrap();
+        a.add_file_with_attrs(
+            &script,
+            "tools/bin/script",
+            Attrs {
+                user: "daemon".into(),
+                uid: 1,
+                group: "other".into(),
+                gid: 1,
+                ..Attrs::file().mode(0o555)
+            },
+        )
+        .unwrap();
+        assert!(a.add_file_with_data(vec![], "../escape").is_err());
+        assert!(a.add_file_with_data(vec![], "a//b").is_err());
+        assert!(a.add_symlink("link", "").is_err());
+        a.finish().unwrap();
+
+        let c = Contents::read(&p).unwrap();
+        let modes: Vec<_> =
+            c.entries.iter().map(|e| (e.name.as_str(), e.mode)).collect();
+        assert_eq!(
+            modes,
+            vec![
+                ("gimlet.rom", 0o444),
+                ("tools/bin/script", 0o555),
+                (SHA256SUMS, 0o444),
+            ]
+        );
+        assert_eq!(c.links["roms/gimlet.rom"], "../gimlet.rom");
+        assert!(c.infos[SHA256SUMS].contains("  tools/bin/script\n"));
+
+        /*
+         * Each directory must come before anything within it, with the
+         * attributes that were asked for, or the default if none were given.
+         */
+        let mut t = tar::Archive::new(
+            compress::decoder(std::fs::File::open(&p).unwrap()).unwrap(),
+        );
+        let hdrs: Vec<_> = t
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let h = e.unwrap().header().clone();
+                (
+                    h.path().unwrap().to_str().unwrap().to_string(),
+                    h.mode().unwrap(),
+                    h.username().unwrap().unwrap().to_string(),
+                    h.groupname().unwrap().unwrap().to_string(),
+                )
+            })
+            .collect();
+        let names: Vec<_> = hdrs.iter().map(|h| h.0.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "oxide.json",
+                "image",
+                "image/gimlet.rom",
+                "image/roms",
+                "image/roms/gimlet.rom",
+                "image/tools",
+                "image/tools/bin",
+                "image/tools/bin/script",
+                "image/SHA256SUMS",
+            ]
+        );
+        let attrs = |n: &str| {
+            let h = hdrs.iter().find(|h| h.0 == n).unwrap();
+            (h.1, h.2.as_str(), h.3.as_str())
+        };
+        assert_eq!(attrs("image/roms"), (0o750, "root", "root"));
+        assert_eq!(attrs("image/tools/bin"), (0o755, "root", "root"));
+        assert_eq!(attrs("image/tools/bin/script"), (0o555, "daemon", "other"));
+
+        let out = dir.join("out");
+        extract(&p, &out).unwrap();
+        let script = out.join("image/tools/bin/script");
+        assert_eq!(
+            std::fs::metadata(&script).unwrap().permissions().mode() & 0o777,
+            0o555
+        );
+        assert_eq!(
+            std::fs::read_link(out.join("image/roms/gimlet.rom")).unwrap(),
+            PathBuf::from("../gimlet.rom")
+        );
+        assert_eq!(
+            std::fs::read_to_string(out.join("image/roms/gimlet.rom")).unwrap(),
+            "rom\n"
+        );
+    }
+
+    #[test]
+    fn worker_failure() {
+        let dir = TempDir::new("archive-fail");
+        let p = dir.join("os.tar.gz");
+        let tmp = dir.join(format!(".os.tar.gz.{}.tmp", std::process::id()));
+
+        /*
+         * An existing archive at the destination must survive a failed attempt
+         * to replace it, and a failed attempt must not leave anything behind.
+         */
+        for old in [None, Some("old archive")] {
+            if let Some(old) = old {
+                std::fs::write(&p, old).unwrap();
+            }
+
+            let a = os_archive(&p);
+            assert!(tmp.exists());
+            a.add_file_with_data(b"one".to_vec(), "dup").unwrap();
+            a.add_file_with_data(b"two".to_vec(), "dup").unwrap();
+
+            /*
+             * The worker fails on its own thread, so the error is reported by
+             * the first attempt to add a file once the failure has been
+             * recorded.
+             */
+            let start = Instant::now();
+            let e = loop {
+                if let Err(e) = a.add_file_with_data(b"more".to_vec(), "more") {
+                    break e;
+                }
+                assert!(start.elapsed().as_secs() < 30, "error not reported");
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            };
+            let msg = "\"image/dup\" has already been added";
+            assert!(e.to_string().contains(msg), "{e}");
+
+            let e = a.finish().unwrap_err();
+            assert!(e.to_string().contains(msg), "{e}");
+            assert!(!tmp.exists());
+            if let Some(old) = old {
+                assert_eq!(std::fs::read_to_string(&p).unwrap(), old);
+            } else {
+                assert!(!p.exists());
+            }
+        }
+    }
+
+    #[test]
+    fn sparse() {
+        use std::os::unix::fs::FileExt;
+
+        let dir = TempDir::new("archive-sparse");
+
+        /*
+         * The first file has holes on disk, with enough separate regions of
+         * data that the sparse header needs extension blocks, and ends in a
+         * hole.  The second has no holes at all, but has long runs of zeroes
+         * that we should find by reading it.
+         */
+        let holes = dir.join("holes.img");
+        let f = File::create(&holes).unwrap();
+        for i in 0..40u64 {
+            let data = format!("region {i}\n").repeat(100 + i as usize * 50);
+            f.write_all_at(data.as_bytes(), i * 300 * 1024 + 7).unwrap();
+        }
+        f.set_len(16 * 1024 * 1024 + 100).unwrap();
+        drop(f);
+
+        let zeroes = dir.join("zeroes.img");
+        let mut data = vec![0u8; 1024 * 1024];
+        data[1000..2000].fill(0xa5);
+        data[700 * 1024..700 * 1024 + 3].fill(0x5a);
+        std::fs::write(&zeroes, &data).unwrap();
+
+        let dense = dir.join("dense.img");
+        std::fs::write(&dense, vec![1u8; 100 * 1024]).unwrap();
+        assert!(sparse::data_regions(&mut File::open(&dense).unwrap())
+            .unwrap()
+            .is_none());
+        let r = sparse::data_regions(&mut File::open(&zeroes).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(r.iter().map(|r| r.length).sum::<u64>(), 2 * 64 * 1024);
+
+        let p = dir.join("os.tar.gz");
+        let a = os_archive(&p);
+        a.add_sparse_file(&holes, "holes.img").unwrap();
+        a.add_sparse_file(&zeroes, "zeroes.img").unwrap();
+        a.add_sparse_file(&dense, "dense.img").unwrap();
+        a.finish().unwrap();
+
+        let mut t = tar::Archive::new(
+            compress::decoder(File::open(&p).unwrap()).unwrap(),
+        );
+        let types: Vec<_> = t
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                (
+                    e.path().unwrap().display().to_string(),
+                    e.header().entry_type(),
+                )
+            })
+            .filter(|(n, _)| n.ends_with(".img"))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("image/holes.img".to_string(), tar::EntryType::GNUSparse),
+                ("image/zeroes.img".to_string(), tar::EntryType::GNUSparse),
+                ("image/dense.img".to_string(), tar::EntryType::Regular),
+            ]
+        );
+
+        /*
+         * Both the manifest and the hashes we compute when reading the archive
+         * must cover the whole file, holes included, and the file must come
+         * back out exactly as it went in.
+         */
+        let c = Contents::read(&p).unwrap();
+        let sums = parse_sums(&c.infos[SHA256SUMS]).unwrap();
+        let out = dir.join("out");
+        extract(&p, &out).unwrap();
+        for (name, orig) in [
+            ("holes.img", &holes),
+            ("zeroes.img", &zeroes),
+            ("dense.img", &dense),
+        ] {
+            let sha256 = file_sha256(orig).unwrap();
+            let e = c.entries.iter().find(|e| e.name == name).unwrap();
+            assert_eq!(e.sha256, sha256, "{name}");
+            assert_eq!(
+                e.size,
+                std::fs::metadata(orig).unwrap().len(),
+                "{name}"
+            );
+            assert_eq!(sums[name], sha256, "{name}");
+
+            let x = out.join("image").join(name);
+            assert!(std::fs::read(&x).unwrap() == std::fs::read(orig).unwrap());
+            assert_eq!(file_sha256(&x).unwrap(), sha256, "{name}");
+        }
     }
 }
@@ -0,0 +1,152 @@
+/*
+ * Copyright 2026 Oxide Computer Company
+ */
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+
+use crate::archive::hex;
+
+/*
+ * An Oxide boot image, as produced by mkimage, begins with a header which is
+ * padded out to 4KiB.  All integers are little-endian:
+ *
+ *      offset  size    field
+ *      0       4       magic
+ *      4       4       version
+ *      8       8       flags
+ *      16      8       data size (bytes following the header)
+ *      24      8       image size (bytes once decompressed)
+ *      32      8       target size
+ *      40      32      SHA-256 of the image
+ *      72      128     dataset name
+ *      200     128     image name
+ */
+const HEADER_SIZE: usize = 4096;
+const HEADER_MAGIC: u32 = 0x1DEB0075;
+const FLAG_COMPRESSED: u64 = 0x1;
+
+pub struct Summary {
+    pub version: u32,
+    pub compressed: bool,
+    pub data_size: u64,
+    pub image_size: u64,
+    pub target_size: u64,
+    pub dataset_name: String,
+    pub image_name: String,
+    /**
+     * The checksum recorded in the image header, as hexadecimal.
+     */
+    pub checksum: String,
+    /**
+     * The checksum computed over the data which follows the header.  This is
+     * only available for uncompressed images, where the recorded checksum
+     * covers the data as stored.
+     */
+    pub computed: Option<String>,
+}
+
+impl Summary {
+    /**
+     * Determine whether the data in the image matches the checksum in the
+     * header.  Returns None if the check could not be performed.
+     */
+    pub fn data_ok(&self) -> Option<bool> {
+        self.computed.as_ref().map(|c| *c == self.checksum)
+    }
+}
+
+/**
+ * Accept the contents of a boot image as a stream of bytes, so that it can be
+ * checked without holding the (potentially very large) image in memory.
+ */
+pub struct Checker {
+    header: Vec<u8>,
+    hasher: Sha256,
+    data: u64,
+}
+
+impl Default for Checker {
+    fn default() -> Self {
+        Checker {
+            header: Vec::with_capacity(HEADER_SIZE),
+            hasher: Sha256::new(),
+            data: 0,
+        }
+    }
+}
+
+fn cstr(buf: &[u8]) -> String {
+    let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).to_string()
+}
+
+impl Checker {
+    pub fn finish(self) -> Result<Summary> {
+        let h = self.header;
+        if h.len() < HEADER_SIZE {
+            bail!("boot image is too short ({} bytes) for a header", h.len());
+        }
+
+        let u32_at =
+            |o: usize| u32::from_le_bytes(h[o..o + 4].try_into().unwrap());
+        let u64_at =
+            |o: usize| u64::from_le_bytes(h[o..o + 8].try_into().unwrap());
+
+        let magic = u32_at(0);
+        if magic != HEADER_MAGIC {
+            bail!("boot image has bad magic {magic:#x}");
+        }
+
+        let flags = u64_at(8);
+        let data_size = u64_at(16);
+        if data_size != self.data {
+            bail!(
+                "boot image header reports {data_size} bytes of data, \
+                but {} bytes follow the header",
+                self.data,
+            );
+        }
+
+        let compressed = (flags & FLAG_COMPRESSED) != 0;
+        let computed =
+            if compressed { None } else { Some(hex(&self.hasher.finalize())) };
+
+        Ok(Summary {
+            version: u32_at(4),
+            compressed,
+            data_size,
+            image_size: u64_at(24),
+            target_size: u64_at(32),
+            checksum: hex(&h[40..72]),
+            dataset_name: cstr(&h[72..200]),
+            image_name: cstr(&h[200..328]),
+            computed,
+        })
+    }
+}
+
+impl Write for Checker {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut buf = buf;
+        let sz = buf.len();
+
+        if self.header.len() < HEADER_SIZE {
+            let take = buf.len().min(HEADER_SIZE - self.header.len());
+            self.header.extend_from_slice(&buf[..take]);
+            buf = &buf[take..];
+        }
+
+        if !buf.is_empty() {
+            self.hasher.update(buf);
+            self.data += buf.len() as u64;
+        }
+
+        Ok(sz)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
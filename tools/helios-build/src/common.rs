@@ -6,27 +6,89 @@ use anyhow::{bail, Result};
 use serde::Deserialize;
 use slog::{Drain, Logger};
 use std::io::IsTerminal;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
 pub use slog::{info, o};
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Term,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "term" => Ok(LogFormat::Term),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("unknown log format {s:?} (expected term or json)"),
+        }
+    }
+}
+
+pub struct LogOptions {
+    pub format: LogFormat,
+    pub level: slog::Level,
+    /**
+     * If specified, records are also appended to this file as JSON, so that
+     * they can be ingested elsewhere regardless of the format on stdout.
+     */
+    pub file: Option<PathBuf>,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions {
+            format: LogFormat::Term,
+            level: slog::Level::Info,
+            file: None,
+        }
+    }
+}
+
 /**
  * Initialise a logger which writes to stdout, and which does the right thing on
  * both an interactive terminal and when stdout is not a tty.
  */
-pub fn init_log() -> Logger {
-    let dec = slog_term::TermDecorator::new().stdout().build();
-    if std::io::stdout().is_terminal() {
-        let dr = Mutex::new(slog_term::CompactFormat::new(dec).build()).fuse();
-        slog::Logger::root(dr, o!())
+pub fn init_log(lo: &LogOptions) -> Result<Logger> {
+    let stdout = match lo.format {
+        LogFormat::Json => {
+            let dr = slog_json::Json::new(std::io::stdout())
+                .add_default_keys()
+                .build();
+            Logger::root(Mutex::new(dr).fuse(), o!())
+        }
+        LogFormat::Term => {
+            let dec = slog_term::TermDecorator::new().stdout().build();
+            if std::io::stdout().is_terminal() {
+                let dr = slog_term::CompactFormat::new(dec).build();
+                Logger::root(Mutex::new(dr).fuse(), o!())
+            } else {
+                let dr = slog_term::FullFormat::new(dec)
+                    .use_original_order()
+                    .build();
+                Logger::root(Mutex::new(dr).fuse(), o!())
+            }
+        }
+    };
+
+    let file = if let Some(p) = &lo.file {
+        let f = std::fs::OpenOptions::new().create(true).append(true).open(p);
+        let f = match f {
+            Ok(f) => f,
+            Err(e) => bail!("opening log file {p:?}: {e}"),
+        };
+        let dr = slog_json::Json::new(f).add_default_keys().build();
+        Logger::root(Mutex::new(dr).fuse(), o!())
     } else {
-        let dr = Mutex::new(
-            slog_term::FullFormat::new(dec).use_original_order().build(),
-        )
-        .fuse();
-        slog::Logger::root(dr, o!())
-    }
+        Logger::root(slog::Discard, o!())
+    };
+
+    let dr = slog::Duplicate::new(stdout, file).fuse();
+    Ok(Logger::root(dr.filter_level(lo.level).fuse(), o!()))
 }
 
 pub fn sleep(s: u64) {
@@ -0,0 +1,435 @@
+/*
+ * Copyright 2026 Oxide Computer Company
+ */
+
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+
+/*
+ * When compressing with more than one thread, the input is split into blocks
+ * of this size which are compressed independently.  The block size is fixed,
+ * rather than derived from the thread count, so that the output is the same
+ * for any number of threads greater than one.  A single thread produces one
+ * ordinary stream instead, which differs from the block output.
+ */
+const BLOCK_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Codec {
+    fn levels(&self) -> std::ops::RangeInclusive<u32> {
+        match self {
+            Codec::Gzip => 0..=9,
+            Codec::Zstd => 1..=22,
+            Codec::Xz => 0..=9,
+        }
+    }
+
+    fn default_level(&self) -> u32 {
+        match self {
+            Codec::Gzip => 9,
+            Codec::Zstd => 19,
+            Codec::Xz => 6,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: u32,
+    pub threads: usize,
+}
+
+impl Default for Compression {
+    /**
+     * The default is a gzip stream at the best compression level, produced
+     * by a single thread, which any tar(1) or gzip(1) can read.
+     */
+    fn default() -> Self {
+        Compression { codec: Codec::Gzip, level: 9, threads: 1 }
+    }
+}
+
+impl Compression {
+    /**
+     * Parse a compression specification of the form "CODEC[:LEVEL]"; e.g.,
+     * "gzip", "zstd:19", or "xz:6".
+     */
+    pub fn parse(spec: &str) -> Result<Compression> {
+        let (name, level) = match spec.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (spec, None),
+        };
+
+        let codec = match name {
+            "gzip" | "gz" => Codec::Gzip,
+            "zstd" | "zst" => Codec::Zstd,
+            "xz" => Codec::Xz,
+            other => bail!("unknown compression {other:?}"),
+        };
+
+        let level = if let Some(level) = level {
+            let level: u32 = level.parse()?;
+            if !codec.levels().contains(&level) {
+                bail!(
+                    "{name} compression level must be in the range {:?}",
+                    codec.levels()
+                );
+            }
+            level
+        } else {
+            codec.default_level()
+        };
+
+        Ok(Compression { codec, level, threads: 1 })
+    }
+
+    /**
+     * The file name suffix for a tar archive compressed this way.
+     */
+    pub fn suffix(&self) -> &'static str {
+        match self.codec {
+            Codec::Gzip => "tar.gz",
+            Codec::Zstd => "tar.zst",
+            Codec::Xz => "tar.xz",
+        }
+    }
+
+    pub fn encoder<W: Write + Send + 'static>(
+        &self,
+        w: W,
+    ) -> Result<Encoder<W>> {
+        if self.threads > 1 {
+            return Ok(Encoder::Blocks(BlockEncoder::new(*self, w)));
+        }
+
+        Ok(match self.codec {
+            /*
+             * Use an explicit modification time of zero in the gzip header,
+             * rather than relying on the default, so that the compressed
+             * stream does not vary from one build to the next.
+             */
+            Codec::Gzip => Encoder::Gzip(
+                flate2::GzBuilder::new()
+                    .mtime(0)
+                    .write(w, flate2::Compression::new(self.level)),
+            ),
+            Codec::Zstd => {
+                Encoder::Zstd(zstd::Encoder::new(w, self.level as i32)?)
+            }
+            Codec::Xz => Encoder::Xz(xz2::write::XzEncoder::new(w, self.level)),
+        })
+    }
+
+    /**
+     * Compress a single block as a complete gzip member, zstd frame, or xz
+     * stream.  A sequence of these, concatenated, is a valid file in each
+     * format.
+     */
+    fn block(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self.codec {
+            Codec::Gzip => {
+                let mut gzw = flate2::GzBuilder::new()
+                    .mtime(0)
+                    .write(Vec::new(), flate2::Compression::new(self.level));
+                gzw.write_all(data)?;
+                gzw.finish()?
+            }
+            Codec::Zstd => zstd::encode_all(data, self.level as i32)?,
+            Codec::Xz => {
+                let mut xzw =
+                    xz2::write::XzEncoder::new(Vec::new(), self.level);
+                xzw.write_all(data)?;
+                xzw.finish()?
+            }
+        })
+    }
+}
+
+pub enum Encoder<W: Write + Send + 'static> {
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Blocks(BlockEncoder<W>),
+}
+
+impl<W: Write + Send + 'static> Encoder<W> {
+    /**
+     * Complete the compressed stream and return the underlying writer.
+     */
+    pub fn finish(self) -> Result<W> {
+        Ok(match self {
+            Encoder::Gzip(e) => e.finish()?,
+            Encoder::Zstd(e) => e.finish()?,
+            Encoder::Xz(e) => e.finish()?,
+            Encoder::Blocks(e) => e.finish()?,
+        })
+    }
+}
+
+impl<W: Write + Send + 'static> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+            Encoder::Xz(e) => e.write(buf),
+            Encoder::Blocks(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+            Encoder::Xz(e) => e.flush(),
+            Encoder::Blocks(e) => e.flush(),
+        }
+    }
+}
+
+type Job = (Vec<u8>, mpsc::Sender<Result<Vec<u8>>>);
+
+fn ioerr<S: ToString>(msg: S) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, msg.to_string())
+}
+
+/**
+ * Compress a stream on several threads at once.  The input is collected into
+ * fixed-size blocks, each of which is handed to a pool of worker threads.  The
+ * compressed blocks are written out in their original order as they complete.
+ * To bound memory usage, we wait for the oldest block whenever there are more
+ * than two blocks per thread in flight.
+ */
+pub struct BlockEncoder<W: Write> {
+    w: W,
+    buf: Vec<u8>,
+    wrote_block: bool,
+    jobs: Option<mpsc::Sender<Job>>,
+    pending: VecDeque<mpsc::Receiver<Result<Vec<u8>>>>,
+    workers: Vec<JoinHandle<()>>,
+    threads: usize,
+}
+
+impl<W: Write> BlockEncoder<W> {
+    fn new(c: Compression, w: W) -> BlockEncoder<W> {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        let workers = (0..c.threads)
+            .map(|_| {
+                let rx = Arc::clone(&rx);
+                std::thread::spawn(move || loop {
+                    let job = rx.lock().unwrap().recv();
+                    let Ok((data, res)) = job else {
+                        /*
+                         * The encoder has been finished or dropped.
+                         */
+                        return;
+                    };
+
+                    res.send(c.block(&data)).ok();
+                })
+            })
+            .collect();
+
+        BlockEncoder {
+            w,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            wrote_block: false,
+            jobs: Some(tx),
+            pending: Default::default(),
+            workers,
+            threads: c.threads,
+        }
+    }
+
+    fn submit(&mut self) -> std::io::Result<()> {
+        let data =
+            std::mem::replace(&mut self.buf, Vec::with_capacity(BLOCK_SIZE));
+        let (tx, rx) = mpsc::channel();
+        self.jobs
+            .as_ref()
+            .unwrap()
+            .send((data, tx))
+            .map_err(|_| ioerr("compression worker threads have exited"))?;
+        self.pending.push_back(rx);
+        self.wrote_block = true;
+
+        while self.pending.len() > self.threads * 2 {
+            self.write_oldest()?;
+        }
+        Ok(())
+    }
+
+    fn write_oldest(&mut self) -> std::io::Result<()> {
+        let Some(rx) = self.pending.pop_front() else {
+            return Ok(());
+        };
+
+        let data = rx
+            .recv()
+            .map_err(|_| ioerr("compression worker thread died"))?
+            .map_err(|e| ioerr(format!("compression failure: {e}")))?;
+        self.w.write_all(&data)
+    }
+
+    fn finish(mut self) -> std::io::Result<W> {
+        /*
+         * Submit the final partial block.  If no data was written at all, we
+         * still need one (empty) block so that the result is a valid file.
+         */
+        if !self.buf.is_empty() || !self.wrote_block {
+            self.submit()?;
+        }
+        while !self.pending.is_empty() {
+            self.write_oldest()?;
+        }
+
+        drop(self.jobs.take());
+        for w in self.workers.drain(..) {
+            w.join().unwrap();
+        }
+
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+impl<W: Write> Write for BlockEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let take = buf.len().min(BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..take]);
+        if self.buf.len() == BLOCK_SIZE {
+            self.submit()?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        /*
+         * Flushing only affects data that has already been compressed; we do
+         * not want to cut short the block that is being assembled.
+         */
+        self.w.flush()
+    }
+}
+
+/**
+ * Open a decompressing reader for a compressed stream, determining the format
+ * from the magic number at the start of the stream.  The reader accepts the
+ * concatenated blocks produced by a multi-threaded encoder.
+ */
+pub fn decoder<R: Read + 'static>(r: R) -> Result<Box<dyn Read>> {
+    let mut r = std::io::BufReader::new(r);
+
+    let magic = {
+        use std::io::BufRead;
+        r.fill_buf()?.to_vec()
+    };
+
+    Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::MultiGzDecoder::new(r))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(zstd::Decoder::with_buffer(r)?)
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Box::new(xz2::bufread::XzDecoder::new_multi_decoder(r))
+    } else {
+        bail!("unrecognised compression format");
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        /*
+         * Use enough data to span several blocks when compressing on more than
+         * one thread, so that the decoder must read concatenated members.
+         */
+        let data: Vec<u8> = (0..40 * 1024 * 1024u32)
+            .map(|i| (i / 4096 % 251) as u8 ^ (i % 7) as u8)
+            .collect();
+
+        for spec in ["gzip:1", "zstd:1", "xz:0"] {
+            for threads in [1, 3] {
+                let c = Compression {
+                    threads,
+                    ..Compression::parse(spec).unwrap()
+                };
+                let mut enc = c.encoder(Vec::new()).unwrap();
+                enc.write_all(&data).unwrap();
+                let out = enc.finish().unwrap();
+
+                let mut back = Vec::new();
+                decoder(std::io::Cursor::new(out))
+                    .unwrap()
+                    .read_to_end(&mut back)
+                    .unwrap();
+                assert!(back == data, "{spec} with {threads} threads");
+            }
+
+            /*
+             * Nothing written at all must still produce a valid stream.
+             */
+            let c =
+                Compression { threads: 2, ..Compression::parse(spec).unwrap() };
+            let out = c.encoder(Vec::new()).unwrap().finish().unwrap();
+            let mut back = Vec::new();
+            decoder(std::io::Cursor::new(out))
+                .unwrap()
+                .read_to_end(&mut back)
+                .unwrap();
+            assert!(back.is_empty());
+        }
+
+        let c = Compression::parse("zst").unwrap();
+        assert_eq!((c.codec, c.level, c.threads), (Codec::Zstd, 19, 1));
+        assert_eq!(Compression::parse("xz:9").unwrap().suffix(), "tar.xz");
+        assert_eq!(Compression::parse("gz:0").unwrap().level, 0);
+        assert!(Compression::parse("bzip2").is_err());
+        assert!(Compression::parse("").is_err());
+        assert!(Compression::parse("gzip:10").is_err());
+        assert!(Compression::parse("zstd:0").is_err());
+        assert!(Compression::parse("xz:-1").is_err());
+        assert!(Compression::parse("gzip:").is_err());
+        assert!(Compression::parse("gzip:fast").is_err());
+        let plain = std::io::Cursor::new(b"plain".to_vec());
+        assert!(decoder(plain).is_err());
+    }
+
+    #[test]
+    fn thread_count() {
+        /*
+         * Once there is more than one thread, the output must not depend on
+         * how many there are.  Use enough data for more than two blocks, so
+         * that blocks are finished out of order with enough threads.
+         */
+        let data: Vec<u8> = (0..(2 * BLOCK_SIZE + 12345) as u32)
+            .map(|i| (i / 4096 % 251) as u8 ^ (i % 7) as u8)
+            .collect();
+
+        for spec in ["gzip:1", "zstd:1", "xz:0"] {
+            let out = |threads: usize| {
+                let c = Compression {
+                    threads,
+                    ..Compression::parse(spec).unwrap()
+                };
+                let mut enc = c.encoder(Vec::new()).unwrap();
+                enc.write_all(&data).unwrap();
+                enc.finish().unwrap()
+            };
+            assert!(out(2) == out(8), "{spec}");
+        }
+    }
+}
@@ -3,14 +3,46 @@
  */
 
 use anyhow::{anyhow, bail, Result};
-use slog::{error, info, warn, Logger};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use slog::{error, info, o, warn, Logger};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
 use std::ffi::CString;
-use std::ffi::OsStr;
-use std::fs::{DirBuilder, File};
+use std::ffi::{OsStr, OsString};
+use std::fs::{DirBuilder, File, OpenOptions};
 use std::io::{BufRead, BufReader, Read, Write};
-use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::{
+    DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt,
+};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::platform;
+use crate::report::{self, ExecRecord};
+use crate::tools::Tool;
+use crate::transcript::{self, Transcript};
+
+/*
+ * In dry-run mode, the functions that manage files and directories examine
+ * the file system and report what they would change, without changing it.
+ * The mode is set once for the whole process, so that it applies to every
+ * caller without each of them needing to pass it along.
+ */
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
 
 #[derive(Debug, PartialEq)]
 pub enum FileType {
@@ -29,6 +61,8 @@ pub enum Id {
 pub struct FileInfo {
     pub filetype: FileType,
     pub perms: u32,
+    pub uid: u32,
+    pub gid: u32,
     pub target: Option<PathBuf>, /* for symbolic links */
 }
 
@@ -45,7 +79,7 @@ pub fn check<P: AsRef<Path>>(p: P) -> Result<Option<FileInfo>> {
         Box::into_raw(Box::new(unsafe { std::mem::zeroed::<libc::stat>() }));
     let (r, e, st) = unsafe {
         let r = libc::lstat(cname.as_ptr(), st);
-        let e = *libc::___errno();
+        let e = platform::errno();
         (r, e, Box::from_raw(st))
     };
     if r != 0 {
@@ -76,7 +110,84 @@ pub fn check<P: AsRef<Path>>(p: P) -> Result<Option<FileInfo>> {
 
     let perms = st.st_mode & 0o7777; /* as per mknod(2) */
 
-    Ok(Some(FileInfo { filetype, perms, target }))
+    Ok(Some(FileInfo {
+        filetype,
+        perms,
+        uid: st.st_uid,
+        gid: st.st_gid,
+        target,
+    }))
+}
+
+fn resolve_uid(id: &Id) -> Result<u32> {
+    match id {
+        Id::Id(uid) => Ok(*uid),
+        Id::Name(name) => match crate::illumos::get_passwd_by_name(name)? {
+            Some(pw) => Ok(pw.uid),
+            None => bail!("user {:?} does not exist", name),
+        },
+    }
+}
+
+fn resolve_gid(id: &Id) -> Result<u32> {
+    match id {
+        Id::Id(gid) => Ok(*gid),
+        Id::Name(name) => match crate::illumos::get_group_by_name(name)? {
+            Some(gr) => Ok(gr.gid),
+            None => bail!("group {:?} does not exist", name),
+        },
+    }
+}
+
+/**
+ * Make sure a path has the expected owner and group.  If either is not
+ * specified, it is left as it is.  Symbolic links are changed, rather than
+ * the files they point to.
+ */
+pub fn owner<P: AsRef<Path>>(
+    log: &Logger,
+    p: P,
+    owner: Option<&Id>,
+    group: Option<&Id>,
+) -> Result<bool> {
+    let p = p.as_ref();
+    let log = log.new(slog::o!("path" => p.display().to_string()));
+
+    if owner.is_none() && group.is_none() {
+        return Ok(false);
+    }
+
+    let fi = if let Some(fi) = check(p)? {
+        fi
+    } else {
+        bail!("{} does not exist", p.display());
+    };
+
+    let uid = owner.map(resolve_uid).transpose()?.unwrap_or(fi.uid);
+    let gid = group.map(resolve_gid).transpose()?.unwrap_or(fi.gid);
+    if fi.uid == uid && fi.gid == gid {
+        return Ok(false);
+    }
+
+    info!(log, "owner is {}:{}, should be {}:{}", fi.uid, fi.gid, uid, gid);
+
+    if dry_run() {
+        info!(log, "dry run: would chown");
+        return Ok(true);
+    }
+
+    let cname = CString::new(p.to_str().unwrap().to_string())?;
+    let (r, e) = unsafe {
+        let r = libc::lchown(cname.as_ptr(), uid, gid);
+        let e = platform::errno();
+        (r, e)
+    };
+    if r != 0 {
+        bail!("lchown({}, {}, {}): errno {}", p.display(), uid, gid, e);
+    }
+
+    info!(log, "chown ok");
+    Ok(true)
 }
 
 pub fn perms<P: AsRef<Path>>(log: &Logger, p: P, perms: u32) -> Result<bool> {
@@ -98,10 +209,15 @@ pub fn perms<P: AsRef<Path>>(log: &Logger, p: P, perms: u32) -> Result<bool> {
         did_work = true;
         info!(log, "perms are {:o}, should be {:o}", fi.perms, perms);
 
+        if dry_run() {
+            info!(log, "dry run: would chmod");
+            return Ok(did_work);
+        }
+
         let cname = CString::new(p.to_str().unwrap().to_string())?;
         let (r, e) = unsafe {
             let r = libc::chmod(cname.as_ptr(), perms);
-            let e = *libc::___errno();
+            let e = platform::errno();
             (r, e)
         };
         if r != 0 {
@@ -118,6 +234,8 @@ pub fn directory<P: AsRef<Path>>(
     log: &Logger,
     dir: P,
     mode: u32,
+    owner: Option<&Id>,
+    group: Option<&Id>,
 ) -> Result<bool> {
     let dir = dir.as_ref();
     let mut did_work = false;
@@ -134,6 +252,10 @@ pub fn directory<P: AsRef<Path>>(
          * Create the directory, and all missing parents:
          */
         did_work = true;
+        if dry_run() {
+            info!(log, "dry run: would create directory: {}", dir.display());
+            return Ok(did_work);
+        }
         info!(log, "creating directory: {}", dir.display());
         DirBuilder::new().recursive(true).mode(mode).create(dir)?;
 
@@ -143,6 +265,12 @@ pub fn directory<P: AsRef<Path>>(
         check(dir)?.expect("directory should now exist");
     }
 
+    /*
+     * Changing the owner may clear some mode bits, so we do that first.
+     */
+    if self::owner(log, dir, owner, group)? {
+        did_work = true;
+    }
     if perms(log, dir, mode)? {
         did_work = true;
     }
@@ -179,21 +307,158 @@ fn comparestr<P: AsRef<Path>>(src: &str, dst: P) -> Result<bool> {
     Ok(dstbuf == src.as_bytes())
 }
 
+/*
+ * Files are compared in chunks of this size.
+ */
+const COMPARE_CHUNK: usize = 1024 * 1024;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct HashEntry {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    sha256: String,
+}
+
+struct HashCache {
+    path: PathBuf,
+    entries: BTreeMap<PathBuf, HashEntry>,
+    dirty: bool,
+}
+
+impl HashCache {
+    /*
+     * Write the cache back to disk, if it has changed since it was loaded.
+     * Entries for files that no longer exist are dropped at this point, so
+     * that the cache does not grow without bound.
+     */
+    fn save(&mut self) -> Result<()> {
+        if !self.dirty || dry_run() {
+            return Ok(());
+        }
+
+        self.entries.retain(|p, _| p.exists());
+        let buf = serde_json::to_vec(&self.entries)?;
+        replace(&self.path, 0o644, |f| Ok(f.write_all(&buf)?))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/*
+ * An optional cache of the SHA-256 hashes of file contents, stored on disk and
+ * keyed by path, size, and modification time.  When both files in a
+ * comparison have a current entry, they can be compared without reading them
+ * again.  The cache is held in memory while in use, and written out only when
+ * it is put away.
+ */
+static HASH_CACHE: Mutex<Option<HashCache>> = Mutex::new(None);
+
+/**
+ * Use (or, with None, stop using) a file content hash cache stored in the
+ * specified file.  If the file does not exist or cannot be parsed, the cache
+ * starts out empty.  Any cache already in use is first written back to its
+ * file, so this must be called with None once the cache is no longer needed.
+ */
+pub fn set_hash_cache(p: Option<&Path>) -> Result<()> {
+    let mut hc = HASH_CACHE.lock().unwrap();
+    if let Some(old) = hc.as_mut() {
+        old.save()?;
+    }
+
+    *hc = p.map(|p| {
+        let path = p.to_path_buf();
+        let entries = std::fs::read(&path)
+            .ok()
+            .and_then(|buf| serde_json::from_slice(&buf).ok())
+            .unwrap_or_default();
+
+        HashCache { path, entries, dirty: false }
+    });
+
+    Ok(())
+}
+
+fn read_chunk(r: &mut File, buf: &mut [u8]) -> Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..])? {
+            0 => break,
+            sz => n += sz,
+        }
+    }
+    Ok(n)
+}
+
+/**
+ * Return the hash of the contents of a file, from the hash cache if there is
+ * a current entry, or otherwise by reading the file and then recording the
+ * result.  Returns None if the cache is not in use.
+ */
+fn cached_hash(p: &Path) -> Result<Option<String>> {
+    let mut hc = HASH_CACHE.lock().unwrap();
+    let Some(hc) = hc.as_mut() else {
+        return Ok(None);
+    };
+
+    let mut f = open(p)?;
+    let md = f.metadata()?;
+    let key = std::fs::canonicalize(p)?;
+    let (size, mtime, mtime_nsec) = (md.len(), md.mtime(), md.mtime_nsec());
+
+    if let Some(e) = hc.entries.get(&key) {
+        if e.size == size && e.mtime == mtime && e.mtime_nsec == mtime_nsec {
+            return Ok(Some(e.sha256.clone()));
+        }
+    }
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; COMPARE_CHUNK];
+    loop {
+        let n = read_chunk(&mut f, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let sha256 = crate::archive::hex(&hasher.finalize());
+
+    hc.entries.insert(
+        key,
+        HashEntry { size, mtime, mtime_nsec, sha256: sha256.clone() },
+    );
+    hc.dirty = true;
+
+    Ok(Some(sha256))
+}
+
 fn compare<P1: AsRef<Path>, P2: AsRef<Path>>(src: P1, dst: P2) -> Result<bool> {
-    let srcf = open(src)?;
-    let dstf = open(dst)?;
-    let mut srcr = BufReader::new(srcf);
-    let mut dstr = BufReader::new(dstf);
+    let src = src.as_ref();
+    let dst = dst.as_ref();
+    let mut srcf = open(src)?;
+    let mut dstf = open(dst)?;
 
+    if srcf.metadata()?.len() != dstf.metadata()?.len() {
+        /*
+         * Files are not the same size...
+         */
+        return Ok(false);
+    }
+
+    if let Some(srchash) = cached_hash(src)? {
+        return Ok(cached_hash(dst)?.as_ref() == Some(&srchash));
+    }
+
+    let mut srcbuf = vec![0u8; COMPARE_CHUNK];
+    let mut dstbuf = vec![0u8; COMPARE_CHUNK];
     loop {
-        let mut srcbuf = [0u8; 1];
-        let mut dstbuf = [0u8; 1];
-        let srcsz = srcr.read(&mut srcbuf)?;
-        let dstsz = dstr.read(&mut dstbuf)?;
+        let srcsz = read_chunk(&mut srcf, &mut srcbuf)?;
+        let dstsz = read_chunk(&mut dstf, &mut dstbuf)?;
 
-        if srcsz != dstsz {
+        if srcbuf[..srcsz] != dstbuf[..dstsz] {
             /*
-             * Files are not the same size...
+             * This portion of the read files are not the same.  The file
+             * may also have changed size since we checked.
              */
             return Ok(false);
         }
@@ -205,14 +470,76 @@ fn compare<P1: AsRef<Path>, P2: AsRef<Path>>(src: P1, dst: P2) -> Result<bool> {
              */
             return Ok(true);
         }
+    }
+}
 
-        if srcbuf != dstbuf {
-            /*
-             * This portion of the read files are not the same.
-             */
-            return Ok(false);
+/*
+ * Remove a file that is about to be replaced.
+ */
+fn unlink(log: &Logger, dst: &Path) -> Result<()> {
+    if dry_run() {
+        info!(log, "dry run: would unlink {}", dst.display());
+    } else {
+        std::fs::remove_file(dst)?;
+    }
+    Ok(())
+}
+
+/**
+ * Replace a file without ever leaving it missing or partly written.  The new
+ * contents are written to a temporary file in the same directory, which is
+ * flushed to disk and given the correct mode before it is renamed over the
+ * target.
+ */
+fn replace<F>(dst: &Path, mode: u32, fill: F) -> Result<()>
+where
+    F: FnOnce(&mut File) -> Result<()>,
+{
+    let (Some(dir), Some(name)) = (dst.parent(), dst.file_name()) else {
+        bail!("cannot replace {}", dst.display());
+    };
+    let tmp = dir.join(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    /*
+     * Clear out any temporary file left behind by an earlier process that
+     * happened to have the same ID and was interrupted.
+     */
+    match std::fs::remove_file(&tmp) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            bail!("removing stale {}: {}", tmp.display(), e);
         }
+        _ => (),
+    }
+
+    let res = (|| -> Result<()> {
+        let mut f = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(mode)
+            .open(&tmp)?;
+        fill(&mut f)?;
+        f.set_permissions(std::fs::Permissions::from_mode(mode))?;
+        f.sync_all()?;
+        drop(f);
+
+        std::fs::rename(&tmp, dst)?;
+
+        /*
+         * Flush the directory as well, so that the rename is durable.
+         */
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    })();
+
+    if res.is_err() {
+        std::fs::remove_file(&tmp).ok();
     }
+    res.map_err(|e| anyhow!("replacing \"{}\": {}", dst.display(), e))
 }
 
 pub fn removed<P: AsRef<Path>>(log: &Logger, dst: P) -> Result<()> {
@@ -228,7 +555,11 @@ pub fn removed<P: AsRef<Path>>(log: &Logger, dst: P) -> Result<()> {
                     fi.filetype
                 );
 
-                std::fs::remove_file(dst)?;
+                if dry_run() {
+                    info!(log, "dry run: would remove {}", dst.display());
+                } else {
+                    std::fs::remove_file(dst)?;
+                }
             }
             t => {
                 bail!(
@@ -254,6 +585,8 @@ pub fn file_str<P: AsRef<Path>>(
     contents: &str,
     dst: P,
     mode: u32,
+    owner: Option<&Id>,
+    group: Option<&Id>,
     create: Create,
 ) -> Result<bool> {
     let dst = dst.as_ref();
@@ -306,25 +639,23 @@ pub fn file_str<P: AsRef<Path>>(
                 } else {
                     warn!(
                         log,
-                        "file {} exists, with wrong contents, unlinking",
+                        "file {} exists, with wrong contents, replacing",
                         dst.display()
                     );
-                    std::fs::remove_file(dst)?;
                     true
                 }
             }
             Create::Always => {
                 /*
-                 * We found a file type we don't expect.  Try to unlink it
+                 * We found a file type we don't expect.  Try to replace it
                  * anyway.
                  */
                 warn!(
                     log,
-                    "file {} exists, of type {:?}, unlinking",
+                    "file {} exists, of type {:?}, replacing",
                     dst.display(),
                     fi.filetype
                 );
-                std::fs::remove_file(dst)?;
                 true
             }
         }
@@ -335,16 +666,17 @@ pub fn file_str<P: AsRef<Path>>(
 
     if do_copy {
         did_work = true;
+        if dry_run() {
+            info!(log, "dry run: would write {}", dst.display());
+            return Ok(did_work);
+        }
         info!(log, "writing {} ...", dst.display());
-
-        let mut f = std::fs::OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .open(dst)?;
-        f.write_all(contents.as_bytes())?;
-        f.flush()?;
+        replace(dst, mode, |f| Ok(f.write_all(contents.as_bytes())?))?;
     }
 
+    if self::owner(log, dst, owner, group)? {
+        did_work = true;
+    }
     if perms(log, dst, mode)? {
         did_work = true;
     }
@@ -358,6 +690,8 @@ pub fn file<P1: AsRef<Path>, P2: AsRef<Path>>(
     src: P1,
     dst: P2,
     mode: u32,
+    owner: Option<&Id>,
+    group: Option<&Id>,
     create: Create,
 ) -> Result<bool> {
     let src = src.as_ref();
@@ -411,25 +745,23 @@ pub fn file<P1: AsRef<Path>, P2: AsRef<Path>>(
                 } else {
                     warn!(
                         log,
-                        "file {} exists, with wrong contents, unlinking",
+                        "file {} exists, with wrong contents, replacing",
                         dst.display()
                     );
-                    std::fs::remove_file(dst)?;
                     true
                 }
             }
             Create::Always => {
                 /*
-                 * We found a file type we don't expect.  Try to unlink it
+                 * We found a file type we don't expect.  Try to replace it
                  * anyway.
                  */
                 warn!(
                     log,
-                    "file {} exists, of type {:?}, unlinking",
+                    "file {} exists, of type {:?}, replacing",
                     dst.display(),
                     fi.filetype
                 );
-                std::fs::remove_file(dst)?;
                 true
             }
         }
@@ -440,10 +772,25 @@ pub fn file<P1: AsRef<Path>, P2: AsRef<Path>>(
 
     if do_copy {
         did_work = true;
+        if dry_run() {
+            info!(
+                log,
+                "dry run: would copy {} -> {}",
+                src.display(),
+                dst.display()
+            );
+            return Ok(did_work);
+        }
         info!(log, "copying {} -> {} ...", src.display(), dst.display());
-        std::fs::copy(src, dst)?;
+        replace(dst, mode, |f| {
+            std::io::copy(&mut open(src)?, f)?;
+            Ok(())
+        })?;
     }
 
+    if self::owner(log, dst, owner, group)? {
+        did_work = true;
+    }
     if perms(log, dst, mode)? {
         did_work = true;
     }
@@ -474,7 +821,7 @@ pub fn symlink<P1: AsRef<Path>, P2: AsRef<Path>>(
                     target.display(),
                     fitarget.display()
                 );
-                std::fs::remove_file(dst)?;
+                unlink(log, dst)?;
                 true
             }
         } else {
@@ -487,7 +834,7 @@ pub fn symlink<P1: AsRef<Path>, P2: AsRef<Path>>(
                 dst.display(),
                 fi.filetype
             );
-            std::fs::remove_file(dst)?;
+            unlink(log, dst)?;
             true
         }
     } else {
@@ -497,6 +844,15 @@ pub fn symlink<P1: AsRef<Path>, P2: AsRef<Path>>(
 
     if do_link {
         did_work = true;
+        if dry_run() {
+            info!(
+                log,
+                "dry run: would link {} -> {}",
+                dst.display(),
+                target.display()
+            );
+            return Ok(did_work);
+        }
         info!(log, "linking {} -> {} ...", dst.display(), target.display());
         std::os::unix::fs::symlink(target, dst)?;
     }
@@ -509,10 +865,55 @@ pub fn symlink<P1: AsRef<Path>, P2: AsRef<Path>>(
     Ok(did_work)
 }
 
+/**
+ * Decides where the tools we run are to be found, and starts the processes
+ * for commands run through this module.  The system runner uses the configured
+ * tool paths; tests may install a runner that substitutes stand-in scripts.
+ */
+pub trait CommandRunner {
+    fn path(&self, tool: Tool) -> String {
+        tool.configured_path()
+    }
+
+    fn spawn(&self, cmd: &mut Command) -> std::io::Result<Child> {
+        cmd.spawn()
+    }
+}
+
+struct SystemRunner;
+
+impl CommandRunner for SystemRunner {}
+
+thread_local! {
+    /*
+     * Unlike our other settings, the runner is chosen for each thread rather
+     * than for the whole process, so that tests running in parallel can each
+     * use their own.
+     */
+    static RUNNER: RefCell<Rc<dyn CommandRunner>> =
+        RefCell::new(Rc::new(SystemRunner));
+}
+
+pub fn runner() -> Rc<dyn CommandRunner> {
+    RUNNER.with(|r| r.borrow().clone())
+}
+
+#[cfg(test)]
+pub fn set_runner(runner: Rc<dyn CommandRunner>) {
+    RUNNER.with(|r| *r.borrow_mut() = runner);
+}
+
+/*
+ * Each line of output from a command is logged as a separate record, tagged
+ * with the program and the stream ("stdout" or "stderr") it came from.
+ */
 fn spawn_reader<T>(
     log: &Logger,
+    prog: &str,
     name: &str,
     stream: Option<T>,
+    transcript: Option<Arc<Transcript>>,
+    tail: Tail,
 ) -> Option<std::thread::JoinHandle<()>>
 where
     T: Read + Send + 'static,
@@ -520,7 +921,10 @@ where
     let name = name.to_string();
     let stream = stream?;
 
-    let log = log.clone();
+    let log = log.new(o!(
+        "cmd" => prog.to_string(),
+        "stream" => if name == "E" { "stderr" } else { "stdout" },
+    ));
 
     Some(std::thread::spawn(move || {
         let mut r = BufReader::new(stream);
@@ -541,11 +945,16 @@ where
                     return;
                 }
                 Ok(_) => {
+                    if let Some(t) = &transcript {
+                        t.line(&name, &buf);
+                    }
+                    keep(&tail, &buf);
+
                     let s = String::from_utf8_lossy(&buf);
                     let s = s.trim();
 
                     if !s.is_empty() {
-                        info!(log, "{}| {}", name, s);
+                        info!(log, "{}", s);
                     }
                 }
                 Err(e) => {
@@ -557,52 +966,764 @@ where
     }))
 }
 
-pub fn run2(log: &Logger, cmd: &mut Command) -> Result<()> {
-    let mut logargs = vec![cmd.get_program().to_owned()];
+/**
+ * How long a command may run before it is terminated.  Each call site picks
+ * the policy that suits the command it runs.
+ */
+#[derive(Clone, Copy, Debug)]
+pub enum Timeout {
+    /**
+     * Commands that should complete in a few minutes at most; e.g.,
+     * "pkgrepo refresh".
+     */
+    Short,
+    /**
+     * Commands that transfer data over the network; e.g., "rustup toolchain
+     * install".  Receiving packages from a local build repository with
+     * "pkgrecv" is part of the build, and uses Build.
+     */
+    Network,
+    /**
+     * Commands that build or assemble software; e.g., "cargo build".
+     */
+    Build,
+    /**
+     * A specific limit, for a command that does not fit any of the classes
+     * above.
+     */
+    After(Duration),
+    /**
+     * Commands that are known to run for many hours; e.g., a full illumos
+     * nightly(1) build.
+     */
+    Never,
+}
+
+impl Timeout {
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            Timeout::Short => Some(Duration::from_secs(30 * 60)),
+            Timeout::Network => Some(Duration::from_secs(2 * 3600)),
+            Timeout::Build => Some(Duration::from_secs(6 * 3600)),
+            Timeout::After(d) => Some(*d),
+            Timeout::Never => None,
+        }
+    }
+}
+
+/**
+ * Whether, and how often, a command may be run again after it fails.  Each
+ * call site must declare a policy: only commands that can safely be repeated,
+ * such as a "git fetch" or a download into a fresh location, should use
+ * anything other than Retry::NEVER.  Even then, a command is only run again
+ * if its output suggests the failure was transient; see is_transient().
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct Retry {
+    /**
+     * The total number of times to run the command, including the first.
+     */
+    pub attempts: u32,
+    /**
+     * How long to wait before the second attempt.  The delay doubles for
+     * each subsequent attempt, up to MAX_BACKOFF.
+     */
+    pub backoff: Duration,
+}
+
+impl Retry {
+    /**
+     * Commands that must not be repeated, or whose failures are never
+     * transient; e.g., anything that only reads or writes local files.
+     */
+    pub const NEVER: Retry = Retry { attempts: 1, backoff: Duration::ZERO };
+
+    /**
+     * Commands that fetch from a remote service and may be repeated without
+     * harm; e.g., "git fetch" or "rustup toolchain install".
+     */
+    pub const NETWORK: Retry =
+        Retry { attempts: 4, backoff: Duration::from_secs(15) };
+
+    /**
+     * How long to wait after the specified (1-based) failed attempt.
+     */
+    pub fn delay(&self, attempt: u32) -> Duration {
+        1u32.checked_shl(attempt.saturating_sub(1))
+            .and_then(|factor| self.backoff.checked_mul(factor))
+            .map(|d| d.min(MAX_BACKOFF))
+            .unwrap_or(MAX_BACKOFF)
+    }
+}
+
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/*
+ * Messages, in lower case, that indicate a command failed because of a
+ * problem with the network or with a remote service, rather than a problem
+ * with the command or with what it was asked to do.  These cover git(1),
+ * curl(1) (as used by git and by pkg(7), which reports "Framework error" for
+ * transport failures), rustup(1), and cargo(1).
+ */
+const TRANSIENT: &[&str] = &[
+    "could not resolve host",
+    "temporary failure in name resolution",
+    "name or service not known",
+    "connection timed out",
+    "connection reset",
+    "connection refused",
+    "connection closed",
+    "operation timed out",
+    "network is unreachable",
+    "no route to host",
+    "the remote end hung up unexpectedly",
+    "early eof",
+    "rpc failed",
+    "ssl_error",
+    "gnutls recv error",
+    "framework error",
+    "502 bad gateway",
+    "503 service unavailable",
+    "504 gateway timeout",
+    "could not download file",
+    "error decoding response body",
+    "failed to download",
+    "spurious network error",
+];
+
+/*
+ * How many lines of output to keep from each command for the classifier.
+ */
+const TAIL_LINES: usize = 100;
+
+type Tail = Arc<Mutex<VecDeque<String>>>;
+
+fn keep(tail: &Tail, buf: &[u8]) {
+    let mut tail = tail.lock().unwrap();
+    if tail.len() == TAIL_LINES {
+        tail.pop_front();
+    }
+    tail.push_back(String::from_utf8_lossy(buf).to_lowercase());
+}
+
+/**
+ * Decide whether the output of a failed command suggests that the failure was
+ * transient, such that running the command again might succeed.
+ */
+pub fn is_transient<S: AsRef<str>>(lines: &[S]) -> bool {
+    lines.iter().any(|l| {
+        let l = l.as_ref().to_lowercase();
+        TRANSIENT.iter().any(|t| l.contains(t))
+    })
+}
+
+/*
+ * Once a command has exceeded its timeout and been sent SIGTERM, it has this
+ * long to exit before we send SIGKILL.
+ */
+const KILL_GRACE: Duration = Duration::from_secs(10);
+
+/*
+ * Each command runs in its own process group, so that it can be terminated
+ * along with anything it has started.  A ^C at the terminal is only delivered
+ * to the foreground process group, though, so we pass on such signals to the
+ * groups of any commands that are running.  The list must be usable from a
+ * signal handler, so it is a fixed set of slots rather than a collection.
+ */
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_GROUP: AtomicI32 = AtomicI32::new(0);
+static GROUPS: [AtomicI32; 16] = [NO_GROUP; 16];
+static FORWARD_SIGNALS: Once = Once::new();
+
+extern "C" fn forward_signal(sig: libc::c_int) {
+    for g in GROUPS.iter() {
+        let pgid = g.load(Ordering::SeqCst);
+        if pgid > 0 {
+            unsafe { libc::kill(-pgid, sig) };
+        }
+    }
+
+    /*
+     * Now that the commands have been told, handle the signal as we would
+     * have without this handler.
+     */
+    unsafe {
+        libc::signal(sig, libc::SIG_DFL);
+        libc::raise(sig);
+    }
+}
+
+fn forward_signals() {
+    FORWARD_SIGNALS.call_once(|| {
+        for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            let handler = forward_signal as extern "C" fn(libc::c_int);
+            let prev =
+                unsafe { libc::signal(sig, handler as libc::sighandler_t) };
+            if prev == libc::SIG_IGN {
+                /*
+                 * If the signal was being ignored, as it is for processes
+                 * started with nohup(1), leave it that way.
+                 */
+                unsafe { libc::signal(sig, libc::SIG_IGN) };
+            }
+        }
+    });
+}
+
+/**
+ * Record the process group of a running command, so that signals can be
+ * forwarded to it; the slot is released when this is dropped.
+ */
+struct Group(Option<&'static AtomicI32>);
+
+impl Group {
+    fn register(log: &Logger, pgid: i32) -> Group {
+        let slot = GROUPS.iter().find(|g| {
+            g.compare_exchange(0, pgid, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        });
+        if slot.is_none() {
+            warn!(
+                log,
+                "too many commands running at once; an interrupt will not be \
+                passed on to process group {pgid}"
+            );
+        }
+
+        Group(slot)
+    }
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        if let Some(g) = self.0 {
+            g.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+fn signal_group(pgid: i32, sig: libc::c_int) {
+    /*
+     * The group may already be gone, so errors are not interesting here.
+     */
+    unsafe { libc::kill(-pgid, sig) };
+}
+
+extern "C" {
+    /*
+     * wait4(3C) is available on illumos, but the libc crate does not expose
+     * it there.
+     */
+    fn wait4(
+        pid: libc::pid_t,
+        status: *mut libc::c_int,
+        options: libc::c_int,
+        rusage: *mut libc::rusage,
+    ) -> libc::pid_t;
+}
+
+/**
+ * How a process ended, and the resources it consumed.
+ */
+struct Reaped {
+    status: ExitStatus,
+    rusage: libc::rusage,
+}
+
+/**
+ * Collect the exit status of a child process, along with its resource usage.
+ * Returns None if the process has not yet exited and "block" is false.
+ */
+fn reap(child: &Child, block: bool) -> Result<Option<Reaped>> {
+    let mut status = 0;
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    let options = if block { 0 } else { libc::WNOHANG };
+
+    loop {
+        let r = unsafe {
+            wait4(child.id() as libc::pid_t, &mut status, options, &mut rusage)
+        };
+        if r < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            bail!("wait4: {e}");
+        }
+
+        return Ok((r != 0)
+            .then(|| Reaped { status: ExitStatus::from_raw(status), rusage }));
+    }
+}
+
+fn reap_blocking(child: &Child) -> Result<Reaped> {
+    Ok(reap(child, true)?.expect("blocking wait4 returned early"))
+}
+
+fn poll(child: &Child, limit: Duration) -> Result<Option<Reaped>> {
+    let start = Instant::now();
+    loop {
+        if let Some(r) = reap(child, false)? {
+            return Ok(Some(r));
+        }
+        if start.elapsed() >= limit {
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+struct Waited {
+    reaped: Reaped,
+    timed_out: bool,
+}
+
+/**
+ * Wait for a command to exit.  If it runs for longer than the timeout, its
+ * process group is sent SIGTERM and then, if that does not work, SIGKILL.
+ */
+fn wait(
+    log: &Logger,
+    child: &Child,
+    args: &[&OsStr],
+    timeout: Timeout,
+) -> Result<Waited> {
+    let start = Instant::now();
+
+    let Some(limit) = timeout.duration() else {
+        return Ok(Waited { reaped: reap_blocking(child)?, timed_out: false });
+    };
+    if let Some(reaped) = poll(child, limit)? {
+        return Ok(Waited { reaped, timed_out: false });
+    }
+
+    let pgid = child.id() as i32;
+    warn!(
+        log,
+        "exec {:?}: timed out after {} seconds; terminating",
+        args,
+        start.elapsed().as_secs()
+    );
+    signal_group(pgid, libc::SIGTERM);
+    let reaped = poll(child, KILL_GRACE)?;
+    if reaped.is_none() {
+        warn!(log, "exec {:?}: still running; killing", args);
+    }
+
+    /*
+     * Even if the command itself has exited, anything it started may still
+     * be running and holding its output open.
+     */
+    signal_group(pgid, libc::SIGKILL);
+    let reaped = match reaped {
+        Some(reaped) => reaped,
+        None => reap_blocking(child)?,
+    };
+
+    Ok(Waited { reaped, timed_out: true })
+}
+
+fn args_of(cmd: &Command) -> Vec<OsString> {
+    let mut args = vec![cmd.get_program().to_owned()];
     for arg in cmd.get_args() {
-        logargs.push(arg.to_owned());
+        args.push(arg.to_owned());
+    }
+    args
+}
+
+/**
+ * Decide whether to make another attempt at a command that has failed, and
+ * if so, wait for the appropriate delay first.
+ */
+fn should_retry(
+    log: &Logger,
+    args: &[&OsStr],
+    retry: Retry,
+    attempt: u32,
+    tail: &Tail,
+) -> bool {
+    if attempt >= retry.attempts {
+        return false;
+    }
+
+    let mut lines = tail.lock().unwrap();
+    if !is_transient(lines.make_contiguous()) {
+        return false;
     }
-    info!(log, "exec: {:?}", &logargs; "pwd" => ?cmd.get_current_dir());
+
+    let delay = retry.delay(attempt);
+    warn!(
+        log,
+        "exec {:?}: failure looks transient; retrying in {} seconds \
+        (attempt {} of {})",
+        args,
+        delay.as_secs(),
+        attempt + 1,
+        retry.attempts,
+    );
+    std::thread::sleep(delay);
+    true
+}
+
+/*
+ * A transcript is a convenience, so if we cannot write one we press on
+ * without it.
+ */
+fn start_transcript(
+    log: &Logger,
+    args: &[&OsStr],
+    cmd: &Command,
+    inherit_env: bool,
+    started: SystemTime,
+) -> Option<Arc<Transcript>> {
+    match Transcript::start(args, cmd, inherit_env, started) {
+        Ok(t) => {
+            let t = t.map(Arc::new);
+            if let Some(t) = &t {
+                info!(log, "transcript: {}", t.path().display());
+            }
+            t
+        }
+        Err(e) => {
+            warn!(log, "could not create transcript: {e}");
+            None
+        }
+    }
+}
+
+fn finish_transcript(
+    log: &Logger,
+    transcript: Option<&Arc<Transcript>>,
+    finished: SystemTime,
+    duration: Duration,
+    status: &str,
+) {
+    if let Some(t) = transcript {
+        if let Err(e) = t.finish(finished, duration, status) {
+            warn!(log, "could not complete transcript: {e}");
+        }
+    }
+}
+
+/**
+ * Run a command with the standard input and output of this process, recording
+ * it in the build report.  This is like Command::status(), but without a
+ * timeout, as the command may be interactive.  The standard error of the
+ * command passes through this process so that we can tell whether a failure
+ * was transient, and so that it can be kept in the transcript.
+ */
+pub fn status(
+    log: &Logger,
+    cmd: &mut Command,
+    retry: Retry,
+) -> Result<ExitStatus> {
+    let args = args_of(cmd);
+    let args: Vec<&OsStr> = args.iter().map(|s| s.as_os_str()).collect();
+
+    let mut attempt = 1;
+    loop {
+        let started = SystemTime::now();
+        let start = Instant::now();
+
+        let transcript = start_transcript(log, &args, cmd, true, started);
+
+        cmd.stderr(Stdio::piped());
+        let mut child = runner().spawn(cmd)?;
+
+        let tail: Tail = Default::default();
+        let stderr = child.stderr.take().unwrap();
+        let tee = {
+            let tail = tail.clone();
+            let transcript = transcript.clone();
+            std::thread::spawn(move || {
+                let mut r = stderr;
+                let mut buf = vec![0u8; 8192];
+                let mut line: Vec<u8> = Vec::new();
+                let finish_line = |line: &mut Vec<u8>| {
+                    if let Some(t) = &transcript {
+                        t.line("E", line);
+                    }
+                    keep(&tail, line);
+                    line.clear();
+                };
+
+                loop {
+                    let n = match r.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(e)
+                            if e.kind() == std::io::ErrorKind::Interrupted =>
+                        {
+                            continue
+                        }
+                        Err(_) => break,
+                    };
+
+                    /*
+                     * Pass the output on as soon as it arrives, rather than a
+                     * line at a time, so that prompts and progress updates
+                     * that do not end in a newline are seen straight away.
+                     */
+                    let mut e = std::io::stderr().lock();
+                    e.write_all(&buf[..n]).ok();
+                    e.flush().ok();
+                    drop(e);
+
+                    for &b in &buf[..n] {
+                        line.push(b);
+                        if b == b'\n' {
+                            finish_line(&mut line);
+                        }
+                    }
+                }
+                if !line.is_empty() {
+                    finish_line(&mut line);
+                }
+            })
+        };
+
+        let reaped = reap_blocking(&child)?;
+        tee.join().expect("join stderr thread");
+
+        let finished = SystemTime::now();
+        let duration = start.elapsed();
+        let status = reaped.status.to_string();
+        finish_transcript(
+            log,
+            transcript.as_ref(),
+            finished,
+            duration,
+            &status,
+        );
+        report::record(ExecRecord::new(
+            &args,
+            cmd.get_current_dir(),
+            started,
+            finished,
+            duration,
+            status,
+            reaped.status.success(),
+            Some(&reaped.rusage),
+            transcript.as_ref().map(|t| t.path()),
+        ));
+
+        if reaped.status.success()
+            || !should_retry(log, &args, retry, attempt, &tail)
+        {
+            return Ok(reaped.status);
+        }
+        attempt += 1;
+    }
+}
+
+/*
+ * Read all of one output stream of a child process in the background.
+ */
+fn collect<R: Read + Send + 'static>(
+    mut r: R,
+) -> std::thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+        Ok(buf)
+    })
+}
+
+/**
+ * Run a command and collect its output, recording it in the build report.
+ * This is like Command::output(), but the command is terminated if it runs
+ * for longer than the timeout, as for run().
+ */
+pub fn output(
+    log: &Logger,
+    cmd: &mut Command,
+    timeout: Timeout,
+    retry: Retry,
+) -> Result<Output> {
+    let args = args_of(cmd);
+    let args: Vec<&OsStr> = args.iter().map(|s| s.as_os_str()).collect();
 
     cmd.stdin(Stdio::null());
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
+    cmd.process_group(0);
 
-    let mut child = cmd.spawn()?;
+    let mut attempt = 1;
+    loop {
+        let started = SystemTime::now();
+        let start = Instant::now();
 
-    let readout = spawn_reader(log, "O", child.stdout.take());
-    let readerr = spawn_reader(log, "E", child.stderr.take());
+        let transcript = start_transcript(log, &args, cmd, true, started);
 
-    if let Some(t) = readout {
-        t.join().expect("join stdout thread");
-    }
-    if let Some(t) = readerr {
-        t.join().expect("join stderr thread");
+        forward_signals();
+        let mut child = runner().spawn(cmd)?;
+        let group = Group::register(log, child.id() as i32);
+
+        let readout = collect(child.stdout.take().unwrap());
+        let readerr = collect(child.stderr.take().unwrap());
+
+        let res = wait(log, &child, &args, timeout);
+        drop(group);
+
+        let stdout = readout.join().expect("join stdout thread")?;
+        let stderr = readerr.join().expect("join stderr thread")?;
+
+        /*
+         * The two streams are collected separately, so the transcript has all
+         * of the output of one followed by all of the other.
+         */
+        let finished = SystemTime::now();
+        let duration = start.elapsed();
+        let w = res?;
+        let status = if w.timed_out {
+            format!("timed out after {} seconds", duration.as_secs())
+        } else {
+            w.reaped.status.to_string()
+        };
+        let success = !w.timed_out && w.reaped.status.success();
+        if let Some(t) = &transcript {
+            for (name, out) in [("O", &stdout), ("E", &stderr)] {
+                for l in out.split_inclusive(|&b| b == b'\n') {
+                    t.line(name, l);
+                }
+            }
+        }
+        finish_transcript(
+            log,
+            transcript.as_ref(),
+            finished,
+            duration,
+            &status,
+        );
+        report::record(ExecRecord::new(
+            &args,
+            cmd.get_current_dir(),
+            started,
+            finished,
+            duration,
+            status,
+            success,
+            Some(&w.reaped.rusage),
+            transcript.as_ref().map(|t| t.path()),
+        ));
+
+        if w.timed_out {
+            /*
+             * As in run_once(), a command that used up its time is not run
+             * again.
+             */
+            bail!(
+                "exec {:?}: timed out after running for {} seconds",
+                args,
+                duration.as_secs()
+            );
+        }
+
+        let tail: Tail = Default::default();
+        for l in stderr.split(|&b| b == b'\n') {
+            keep(&tail, l);
+        }
+
+        if success || !should_retry(log, &args, retry, attempt, &tail) {
+            return Ok(Output { status: w.reaped.status, stdout, stderr });
+        }
+        attempt += 1;
     }
+}
 
-    match child.wait() {
-        Err(e) => Err(e.into()),
-        Ok(es) => {
-            if !es.success() {
-                Err(anyhow!("exec {:?}: failed {:?}", &logargs, &es))
-            } else {
-                Ok(())
+/**
+ * Run a command to collect its output, as with output(), for the helpers that
+ * have no logger of their own.  The command is not retried.
+ */
+pub fn capture(cmd: &mut Command, timeout: Timeout) -> Result<Output> {
+    output(&Logger::root(slog::Discard, o!()), cmd, timeout, Retry::NEVER)
+}
+
+/**
+ * Start a command whose standard streams the caller manages itself, through
+ * the runner like every other command we start.
+ */
+pub fn spawn(cmd: &mut Command) -> std::io::Result<Child> {
+    runner().spawn(cmd)
+}
+
+pub fn run2(
+    log: &Logger,
+    cmd: &mut Command,
+    timeout: Timeout,
+    retry: Retry,
+) -> Result<()> {
+    let logargs = args_of(cmd);
+    let args: Vec<&OsStr> = logargs.iter().map(|s| s.as_os_str()).collect();
+
+    run_common(log, cmd, args.as_slice(), true, timeout, retry)
+}
+
+fn run_common(
+    log: &Logger,
+    cmd: &mut Command,
+    args: &[&OsStr],
+    inherit_env: bool,
+    timeout: Timeout,
+    retry: Retry,
+) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        let tail: Tail = Default::default();
+        match run_once(log, cmd, args, inherit_env, timeout, &tail) {
+            Err(e) if should_retry(log, args, retry, attempt, &tail) => {
+                warn!(log, "{e}");
+                attempt += 1;
             }
+            res => return res,
         }
     }
 }
 
-fn run_common(log: &Logger, cmd: &mut Command, args: &[&OsStr]) -> Result<()> {
+fn run_once(
+    log: &Logger,
+    cmd: &mut Command,
+    args: &[&OsStr],
+    inherit_env: bool,
+    timeout: Timeout,
+    tail: &Tail,
+) -> Result<()> {
     info!(log, "exec: {:?}", &args; "pwd" => ?cmd.get_current_dir());
 
     cmd.stdin(Stdio::null());
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
-
-    let mut child = cmd.spawn()?;
-
-    let readout = spawn_reader(log, "O", child.stdout.take());
-    let readerr = spawn_reader(log, "E", child.stderr.take());
+    cmd.process_group(0);
+
+    let started = SystemTime::now();
+    let start = Instant::now();
+
+    let transcript = start_transcript(log, args, cmd, inherit_env, started);
+
+    forward_signals();
+    let mut child = runner().spawn(cmd)?;
+    let group = Group::register(log, child.id() as i32);
+    let prog = transcript::program(args);
+
+    let readout = spawn_reader(
+        log,
+        &prog,
+        "O",
+        child.stdout.take(),
+        transcript.clone(),
+        tail.clone(),
+    );
+    let readerr = spawn_reader(
+        log,
+        &prog,
+        "E",
+        child.stderr.take(),
+        transcript.clone(),
+        tail.clone(),
+    );
+
+    let res = wait(log, &child, args, timeout);
+    drop(group);
 
     if let Some(t) = readout {
         t.join().expect("join stdout thread");
@@ -611,16 +1732,50 @@ fn run_common(log: &Logger, cmd: &mut Command, args: &[&OsStr]) -> Result<()> {
         t.join().expect("join stderr thread");
     }
 
-    match child.wait() {
-        Err(e) => Err(e.into()),
-        Ok(es) => {
-            if !es.success() {
-                Err(anyhow!("exec {:?}: failed {:?}", &args, &es))
-            } else {
-                Ok(())
-            }
-        }
+    let finished = SystemTime::now();
+    let duration = start.elapsed();
+    let w = res?;
+    let status = if w.timed_out {
+        format!("timed out after {} seconds", duration.as_secs())
+    } else {
+        w.reaped.status.to_string()
+    };
+    let success = !w.timed_out && w.reaped.status.success();
+
+    finish_transcript(log, transcript.as_ref(), finished, duration, &status);
+    let see = if let Some(t) = &transcript {
+        format!(" (see {})", t.path().display())
+    } else {
+        String::new()
+    };
+
+    report::record(ExecRecord::new(
+        args,
+        cmd.get_current_dir(),
+        started,
+        finished,
+        duration,
+        status,
+        success,
+        Some(&w.reaped.rusage),
+        transcript.as_ref().map(|t| t.path()),
+    ));
+
+    if w.timed_out {
+        /*
+         * A command that ran until its timeout has already used a long time,
+         * so whatever it printed along the way, we do not run it again.
+         */
+        tail.lock().unwrap().clear();
+        bail!(
+            "exec {:?}: timed out after running for {} seconds{see}",
+            args,
+            duration.as_secs()
+        );
+    } else if !success {
+        bail!("exec {:?}: failed {:?}{see}", &args, &w.reaped.status);
     }
+    Ok(())
 }
 
 pub fn scrub_env(cmd: &mut Command, utf8: bool) {
@@ -642,6 +1797,8 @@ pub fn run_in<S: AsRef<OsStr>, P: AsRef<Path>>(
     log: &Logger,
     pwd: P,
     args: &[S],
+    timeout: Timeout,
+    retry: Retry,
 ) -> Result<()> {
     let args: Vec<&OsStr> = args.iter().map(|s| s.as_ref()).collect();
 
@@ -654,10 +1811,15 @@ pub fn run_in<S: AsRef<OsStr>, P: AsRef<Path>>(
         cmd.args(&args[1..]);
     }
 
-    run_common(log, &mut cmd, args.as_slice())
+    run_common(log, &mut cmd, args.as_slice(), true, timeout, retry)
 }
 
-pub fn run<S: AsRef<OsStr>>(log: &Logger, args: &[S]) -> Result<()> {
+pub fn run<S: AsRef<OsStr>>(
+    log: &Logger,
+    args: &[S],
+    timeout: Timeout,
+    retry: Retry,
+) -> Result<()> {
     let args: Vec<&OsStr> = args.iter().map(|s| s.as_ref()).collect();
 
     let mut cmd = Command::new(args[0]);
@@ -668,10 +1830,15 @@ pub fn run<S: AsRef<OsStr>>(log: &Logger, args: &[S]) -> Result<()> {
         cmd.args(&args[1..]);
     }
 
-    run_common(log, &mut cmd, args.as_slice())
+    run_common(log, &mut cmd, args.as_slice(), true, timeout, retry)
 }
 
-pub fn run_utf8<S: AsRef<OsStr>>(log: &Logger, args: &[S]) -> Result<()> {
+pub fn run_utf8<S: AsRef<OsStr>>(
+    log: &Logger,
+    args: &[S],
+    timeout: Timeout,
+    retry: Retry,
+) -> Result<()> {
     let args: Vec<&OsStr> = args.iter().map(|s| s.as_ref()).collect();
 
     let mut cmd = Command::new(args[0]);
@@ -682,10 +1849,16 @@ pub fn run_utf8<S: AsRef<OsStr>>(log: &Logger, args: &[S]) -> Result<()> {
         cmd.args(&args[1..]);
     }
 
-    run_common(log, &mut cmd, args.as_slice())
+    run_common(log, &mut cmd, args.as_slice(), true, timeout, retry)
 }
 
-pub fn run_env<S, K, V, I>(log: &Logger, args: &[S], env: I) -> Result<()>
+pub fn run_env<S, K, V, I>(
+    log: &Logger,
+    args: &[S],
+    env: I,
+    timeout: Timeout,
+    retry: Retry,
+) -> Result<()>
 where
     S: AsRef<OsStr>,
     I: IntoIterator<Item = (K, V)>,
@@ -703,5 +1876,165 @@ where
         cmd.args(&args[1..]);
     }
 
-    run_common(log, &mut cmd, args.as_slice())
+    run_common(log, &mut cmd, args.as_slice(), false, timeout, retry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{log, TempDir, ENSURE_SETTINGS};
+
+    #[test]
+    fn hash_cache() {
+        let dir = TempDir::new("hash-cache");
+        let log = log();
+        let cache = dir.join("hashes.json");
+
+        let (src, dst, gone) =
+            (dir.join("src"), dir.join("dst"), dir.join("gone"));
+        for p in [&src, &dst, &gone] {
+            std::fs::write(p, "contents\n").unwrap();
+        }
+        let copied = |a: &Path, b: &Path| {
+            file(&log, a, b, 0o644, None, None, Create::Always).unwrap()
+        };
+
+        let _settings =
+            ENSURE_SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+        set_hash_cache(Some(&cache)).unwrap();
+        assert!(!copied(&src, &dst));
+        assert!(!copied(&src, &gone));
+
+        /*
+         * Nothing is written until the cache is put away, and by then any entry
+         * for a file that has since been removed is dropped.
+         */
+        assert!(!cache.exists());
+        std::fs::remove_file(&gone).unwrap();
+        set_hash_cache(None).unwrap();
+        let entries: BTreeMap<PathBuf, serde_json::Value> =
+            serde_json::from_slice(&std::fs::read(&cache).unwrap()).unwrap();
+        assert_eq!(
+            entries.keys().cloned().collect::<Vec<_>>(),
+            vec![
+                std::fs::canonicalize(&dst).unwrap(),
+                std::fs::canonicalize(&src).unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn owner_dry_run() {
+        let dir = TempDir::new("owner");
+        let log = log();
+        let p = dir.join("file");
+        std::fs::write(&p, "contents\n").unwrap();
+        let fi = check(&p).unwrap().unwrap();
+
+        let _settings =
+            ENSURE_SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+
+        /*
+         * Asking for the owner and group that the file has already is not a
+         * change.
+         */
+        let (uid, gid) = (Id::Id(fi.uid), Id::Id(fi.gid));
+        assert!(!owner(&log, &p, None, None).unwrap());
+        assert!(!owner(&log, &p, Some(&uid), Some(&gid)).unwrap());
+
+        /*
+         * In a dry run, a different owner or group is reported as a change,
+         * but the file is left alone.
+         */
+        let (uid, gid) = (Id::Id(fi.uid + 1), Id::Id(fi.gid + 1));
+        set_dry_run(true);
+        let res = [
+            owner(&log, &p, Some(&uid), None),
+            owner(&log, &p, None, Some(&gid)),
+        ];
+        set_dry_run(false);
+        for r in res {
+            assert!(r.unwrap());
+        }
+        assert_eq!(check(&p).unwrap().unwrap(), fi);
+    }
+
+    #[test]
+    fn replace_file() {
+        let dir = TempDir::new("replace");
+        let dst = dir.join("dst");
+        let names = || {
+            let mut names = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        /*
+         * The new contents replace all of the old, even where the old file
+         * was longer, and the temporary file is gone once we are done.
+         */
+        std::fs::write(&dst, "a much longer original file\n").unwrap();
+        replace(&dst, 0o640, |f| Ok(f.write_all(b"new\n")?)).unwrap();
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "new\n");
+        assert_eq!(
+            std::fs::metadata(&dst).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+        assert_eq!(names(), vec!["dst"]);
+
+        /*
+         * If we fail part of the way through writing the new contents, the
+         * original file is left as it was.
+         */
+        let res = replace(&dst, 0o644, |f| {
+            f.write_all(b"partial")?;
+            bail!("interrupted");
+        });
+        assert!(res.is_err());
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "new\n");
+        assert_eq!(names(), vec!["dst"]);
+    }
+
+    #[test]
+    fn run_timeout() {
+        let log = log();
+
+        /*
+         * The whole process group is terminated, not just the shell, or we
+         * would go on waiting for the sleep to close its end of the output
+         * pipe.
+         */
+        let start = Instant::now();
+        let res = run(
+            &log,
+            &["/bin/sh", "-c", "sleep 60; echo done"],
+            Timeout::After(std::time::Duration::from_secs(1)),
+            Retry::NEVER,
+        );
+        assert!(res.is_err());
+        assert!(start.elapsed().as_secs() < 30);
+
+        /*
+         * The same goes for commands whose output we collect.
+         */
+        let start = Instant::now();
+        let res = capture(
+            Command::new("/bin/sh").args(["-c", "sleep 60; echo done"]),
+            Timeout::After(std::time::Duration::from_secs(1)),
+        );
+        assert!(res.is_err());
+        assert!(start.elapsed().as_secs() < 30);
+
+        let out = capture(
+            Command::new("/bin/sh").args(["-c", "echo out; echo err >&2"]),
+            Timeout::Short,
+        )
+        .unwrap();
+        assert!(out.status.success());
+        assert_eq!(out.stdout, b"out\n");
+        assert_eq!(out.stderr, b"err\n");
+    }
 }
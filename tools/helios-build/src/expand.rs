@@ -1,9 +1,10 @@
-use std::collections::HashMap;
 /*
  * Copyright 2024 Oxide Computer Company
  */
 
 use anyhow::{bail, Result};
+use std::collections::{BTreeSet, HashMap};
+use time::{Date, Month, OffsetDateTime, Time};
 
 pub struct Expansion {
     chunks: Vec<Chunk>,
@@ -11,53 +12,176 @@ pub struct Expansion {
 
 enum Chunk {
     Char(char),
+    Expand(Form, Vec<Filter>),
+}
+
+enum Form {
     Simple(String),
-    IfLiteral(String, String),
+    Default(String, String),
+    IfSet(String, String, String),
+}
+
+enum Filter {
+    Upper,
+    Lower,
+    Truncate(usize),
+    Strftime(String),
+    Sanitize,
 }
 
 fn is_variable_char(c: char) -> bool {
-    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+    c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'
 }
 
 /*
  * Current expansion forms:
  *
+ *  ${variable}              expand to "variable" if set, or error if not
+ *  ${variable:-default}     expand to "variable" if set, otherwise to the
+ *                           literal "default"
  *  ${variable?literal}      expand to "literal" if variable is defined,
  *                           otherwise the empty string
- *  ${variable}              expand to "variable" if set, or error if not
+ *  ${!variable?literal}     expand to "literal" if variable is not defined,
+ *                           otherwise the empty string
+ *  ${variable?then:else}    expand to "then" if variable is defined,
+ *                           otherwise to "else"
+ *
+ * Within a literal, a backslash causes the next character to be used as-is,
+ * so that a literal may include "\:", "\|", "\}", "\$", or "\\".
+ *
+ * Any of these forms may be followed by one or more filters, each introduced
+ * by "|", which transform the result in turn:
+ *
+ *  |upper                   convert to upper case
+ *  |lower                   convert to lower case
+ *  |truncate:N              keep at most the first N characters
+ *  |strftime:FORMAT         reformat a date, a time, or a count of seconds
+ *                           since the epoch; e.g., "%Y%m%d" (see strftime())
+ *  |sanitize                replace any character other than a letter, a
+ *                           digit, "-", "_" or "." with "-"
  */
 fn expand(expand: &str) -> Result<Chunk> {
+    /*
+     * Split off any filters at each unescaped "|".  Escapes are left in place
+     * for the expansion itself, but are processed here for filter arguments.
+     */
+    let mut parts = vec![(String::new(), String::new())];
+    let mut chars = expand.chars();
+    while let Some(c) = chars.next() {
+        let (raw, unescaped) = parts.last_mut().unwrap();
+        match c {
+            '|' => parts.push(Default::default()),
+            '\\' => {
+                raw.push(c);
+                if let Some(c) = chars.next() {
+                    raw.push(c);
+                    unescaped.push(c);
+                }
+            }
+            c => {
+                raw.push(c);
+                unescaped.push(c);
+            }
+        }
+    }
+
+    let form = form(&parts[0].0)?;
+    let filters = parts[1..]
+        .iter()
+        .map(|(_, f)| Filter::parse(f))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Chunk::Expand(form, filters))
+}
+
+fn form(expand: &str) -> Result<Form> {
     enum State {
         Variable,
         Literal,
+        Else,
+        Default,
     }
 
     let mut s = State::Variable;
-    let mut chars = expand.chars();
+    let mut chars = expand.chars().peekable();
     let mut variable = String::new();
     let mut literal = String::new();
+    let mut otherwise = String::new();
+
+    let negate = chars.next_if_eq(&'!').is_some();
 
     loop {
+        /*
+         * An escaped character is never treated as punctuation.
+         */
+        let mut c = chars.next();
+        let escaped = c == Some('\\');
+        if escaped {
+            c = chars.next();
+            if c.is_none() {
+                bail!("unexpected end of expansion after \\");
+            }
+        }
+
         match s {
-            State::Variable => match chars.next() {
+            State::Variable => match c {
+                Some(_) if escaped => {
+                    bail!("unexpected escape in variable name");
+                }
                 Some('?') => {
                     if variable.is_empty() {
                         bail!("empty variable unexpected");
                     }
                     s = State::Literal;
                 }
+                Some(':') if chars.next_if_eq(&'-').is_some() => {
+                    if variable.is_empty() {
+                        bail!("empty variable unexpected");
+                    }
+                    if negate {
+                        bail!("a default cannot be used with negation");
+                    }
+                    s = State::Default;
+                }
                 Some(c) if is_variable_char(c) => variable.push(c),
                 Some(c) => bail!("unexpected char in variable name: {:?}", c),
                 None => {
                     if variable.is_empty() {
                         bail!("empty variable unexpected");
                     }
-                    return Ok(Chunk::Simple(variable));
+                    if negate {
+                        bail!("negation requires a literal");
+                    }
+                    return Ok(Form::Simple(variable));
                 }
             },
-            State::Literal => match chars.next() {
+            State::Literal => match c {
+                Some(':') if !escaped => s = State::Else,
                 Some(c) => literal.push(c),
-                None => return Ok(Chunk::IfLiteral(variable, literal)),
+                None => {
+                    return Ok(if negate {
+                        Form::IfSet(variable, String::new(), literal)
+                    } else {
+                        Form::IfSet(variable, literal, String::new())
+                    });
+                }
+            },
+            State::Else => match c {
+                Some(':') if !escaped => {
+                    bail!("unexpected second \":\" in expansion")
+                }
+                Some(c) => otherwise.push(c),
+                None => {
+                    return Ok(if negate {
+                        Form::IfSet(variable, otherwise, literal)
+                    } else {
+                        Form::IfSet(variable, literal, otherwise)
+                    });
+                }
+            },
+            State::Default => match c {
+                Some(c) => otherwise.push(c),
+                None => return Ok(Form::Default(variable, otherwise)),
             },
         }
     }
@@ -110,6 +234,16 @@ impl Expansion {
                         exp.clear();
                         s = State::Rest;
                     }
+                    Some('\\') => {
+                        /*
+                         * Keep the escape for expand(), but make sure the
+                         * escaped character does not end the expansion.
+                         */
+                        exp.push('\\');
+                        if let Some(c) = chars.next() {
+                            exp.push(c);
+                        }
+                    }
                     Some('$') => {
                         bail!("no nesting in expansions for now");
                     }
@@ -124,6 +258,22 @@ impl Expansion {
         }
     }
 
+    /**
+     * Return the names of all of the variables used in the expansion, whether
+     * or not their values would be required to evaluate it.
+     */
+    pub fn variables(&self) -> BTreeSet<&str> {
+        self.chunks
+            .iter()
+            .filter_map(|ch| match ch {
+                Chunk::Char(_) => None,
+                Chunk::Expand(Form::Simple(f), _)
+                | Chunk::Expand(Form::Default(f, _), _)
+                | Chunk::Expand(Form::IfSet(f, _, _), _) => Some(f.as_str()),
+            })
+            .collect()
+    }
+
     pub fn evaluate(
         &self,
         variables: &HashMap<String, String>,
@@ -135,17 +285,31 @@ impl Expansion {
                 Chunk::Char(c) => {
                     out.push(*c);
                 }
-                Chunk::Simple(f) => {
-                    if let Some(v) = variables.get(f) {
-                        out.push_str(v);
-                    } else {
-                        bail!("variable {:?} not defined", f);
-                    }
-                }
-                Chunk::IfLiteral(f, l) => {
-                    if variables.contains_key(f) {
-                        out.push_str(l);
+                Chunk::Expand(form, filters) => {
+                    let mut v = match form {
+                        Form::Simple(f) => {
+                            if let Some(v) = variables.get(f) {
+                                v.to_string()
+                            } else {
+                                bail!("variable {:?} not defined", f);
+                            }
+                        }
+                        Form::Default(f, d) => {
+                            variables.get(f).unwrap_or(d).to_string()
+                        }
+                        Form::IfSet(f, then, otherwise) => {
+                            if variables.contains_key(f) {
+                                then.to_string()
+                            } else {
+                                otherwise.to_string()
+                            }
+                        }
+                    };
+
+                    for f in filters.iter() {
+                        v = f.apply(&v)?;
                     }
+                    out.push_str(&v);
                 }
             }
         }
@@ -153,3 +317,206 @@ impl Expansion {
         Ok(out)
     }
 }
+
+impl Filter {
+    fn parse(f: &str) -> Result<Filter> {
+        let (name, arg) = match f.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (f, None),
+        };
+
+        Ok(match (name, arg) {
+            ("upper", None) => Filter::Upper,
+            ("lower", None) => Filter::Lower,
+            ("sanitize", None) => Filter::Sanitize,
+            ("truncate", Some(n)) => {
+                let Ok(n) = n.parse() else {
+                    bail!("truncate requires a length, not {n:?}");
+                };
+                Filter::Truncate(n)
+            }
+            ("strftime", Some(fmt)) => {
+                /*
+                 * Check the format now, rather than at evaluation time.
+                 */
+                strftime("0", fmt)?;
+                Filter::Strftime(fmt.to_string())
+            }
+            ("upper" | "lower" | "sanitize", Some(_)) => {
+                bail!("filter {name:?} does not take an argument");
+            }
+            ("truncate" | "strftime", None) => {
+                bail!("filter {name:?} requires an argument");
+            }
+            _ => bail!("unknown filter {name:?}"),
+        })
+    }
+
+    fn apply(&self, v: &str) -> Result<String> {
+        Ok(match self {
+            Filter::Upper => v.to_uppercase(),
+            Filter::Lower => v.to_lowercase(),
+            Filter::Truncate(n) => v.chars().take(*n).collect(),
+            Filter::Strftime(fmt) => strftime(v, fmt)?,
+            Filter::Sanitize => v
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                        c
+                    } else {
+                        '-'
+                    }
+                })
+                .collect(),
+        })
+    }
+}
+
+/*
+ * Interpret a value as a date ("YYYY-MM-DD"), a time ("HH:MM:SS"), both
+ * separated by a space or a "T", or a number of seconds since the epoch.
+ */
+fn parse_date_time(v: &str) -> Option<(Option<Date>, Option<Time>)> {
+    let date = |s: &str| -> Option<Date> {
+        let mut t = s.splitn(3, '-');
+        let y = t.next()?.parse().ok()?;
+        let m = Month::try_from(t.next()?.parse::<u8>().ok()?).ok()?;
+        let d = t.next()?.parse().ok()?;
+        Date::from_calendar_date(y, m, d).ok()
+    };
+    let time = |s: &str| -> Option<Time> {
+        let mut t = s.splitn(3, ':');
+        let h = t.next()?.parse().ok()?;
+        let m = t.next()?.parse().ok()?;
+        let s = t.next()?.parse().ok()?;
+        Time::from_hms(h, m, s).ok()
+    };
+
+    let v = v.trim();
+    if let Ok(secs) = v.parse::<i64>() {
+        let dt = OffsetDateTime::from_unix_timestamp(secs).ok()?;
+        Some((Some(dt.date()), Some(dt.time())))
+    } else if let Some((d, t)) = v.split_once([' ', 'T']) {
+        Some((Some(date(d)?), Some(time(t)?)))
+    } else if v.contains('-') {
+        Some((Some(date(v)?), None))
+    } else {
+        Some((None, Some(time(v)?)))
+    }
+}
+
+/**
+ * Format a date or time using a subset of the conversions understood by
+ * strftime(3C): %Y, %y, %m, %d, %e, %j, %H, %M, %S, %F, %T, and %%.
+ */
+fn strftime(v: &str, fmt: &str) -> Result<String> {
+    let Some((date, time)) = parse_date_time(v) else {
+        bail!("cannot interpret {v:?} as a date or time");
+    };
+    let date = || match date {
+        Some(d) => Ok(d),
+        None => bail!("{v:?} does not include a date"),
+    };
+    let time = || match time {
+        Some(t) => Ok(t),
+        None => bail!("{v:?} does not include a time"),
+    };
+
+    let mut out = String::new();
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let s = match chars.next() {
+            Some('Y') => format!("{:04}", date()?.year()),
+            Some('y') => format!("{:02}", date()?.year().rem_euclid(100)),
+            Some('m') => format!("{:02}", date()?.month() as u8),
+            Some('d') => format!("{:02}", date()?.day()),
+            Some('e') => format!("{:>2}", date()?.day()),
+            Some('j') => format!("{:03}", date()?.ordinal()),
+            Some('H') => format!("{:02}", time()?.hour()),
+            Some('M') => format!("{:02}", time()?.minute()),
+            Some('S') => format!("{:02}", time()?.second()),
+            Some('F') => strftime(v, "%Y-%m-%d")?,
+            Some('T') => strftime(v, "%H:%M:%S")?,
+            Some('%') => "%".to_string(),
+            Some(c) => bail!("unsupported conversion %{c} in {fmt:?}"),
+            None => bail!("incomplete conversion at end of {fmt:?}"),
+        };
+        out.push_str(&s);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forms() {
+        let vars: HashMap<String, String> =
+            [("user", "builder"), ("debug", "")]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+        let ex = |t: &str| Expansion::parse(t).and_then(|e| e.evaluate(&vars));
+
+        assert_eq!(ex("${user}@$$").unwrap(), "builder@$");
+        assert!(ex("${host}").is_err());
+        assert_eq!(
+            ex("${host:-unknown}/${user:-x}").unwrap(),
+            "unknown/builder"
+        );
+        assert_eq!(ex("${debug?-DEBUG}${recovery?-R}").unwrap(), "-DEBUG");
+        assert_eq!(ex("${!recovery?normal}${!debug?opt}").unwrap(), "normal");
+        assert_eq!(
+            ex("${debug?dbg:rel} ${recovery?rcv:std}").unwrap(),
+            "dbg std"
+        );
+        assert_eq!(ex("${!debug?a:b}").unwrap(), "b");
+        assert_eq!(ex(r"${debug?a\:b\}\$\\}").unwrap(), r"a:b}$\");
+        assert_eq!(ex(r"${host:-x:y\}}").unwrap(), "x:y}");
+        assert!(ex("${!user}").is_err());
+        assert!(ex("${!user:-x}").is_err());
+        assert!(ex("${debug?a:b:c}").is_err());
+        assert!(ex(r"${us\er}").is_err());
+    }
+
+    #[test]
+    fn filters() {
+        let vars: HashMap<String, String> = [
+            ("user", "builder"),
+            ("date", "2024-03-19"),
+            ("time", "21:01:16"),
+            ("name", "a b/c:d"),
+            ("commit", "49fb31d"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let ex = |t: &str| Expansion::parse(t).and_then(|e| e.evaluate(&vars));
+
+        assert_eq!(ex("${commit|upper}").unwrap(), "49FB31D");
+        assert_eq!(ex("${user|truncate:3|upper}").unwrap(), "BUI");
+        assert_eq!(ex("${date|strftime:%Y%m%d}").unwrap(), "20240319");
+        assert_eq!(ex("${time|strftime:%H%M}").unwrap(), "2101");
+        assert_eq!(ex("${name|sanitize}").unwrap(), "a-b-c-d");
+        assert_eq!(ex("${host:-x y|sanitize}").unwrap(), "x-y");
+        assert_eq!(ex(r"${user?a\|b}").unwrap(), "a|b");
+        assert!(ex("${date|strftime:%H}").is_err());
+        assert!(Expansion::parse("${date|strftime:%Q}").is_err());
+        assert!(Expansion::parse("${user|truncate}").is_err());
+        assert!(Expansion::parse("${user|reverse}").is_err());
+
+        let exp =
+            Expansion::parse("${user}${!debug?x}${host:-y|upper}$$").unwrap();
+        assert_eq!(
+            exp.variables().into_iter().collect::<Vec<_>>(),
+            vec!["debug", "host", "user"]
+        );
+    }
+}
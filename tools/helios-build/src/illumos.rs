@@ -4,19 +4,17 @@
 
 use super::common::{sleep, OutputExt};
 use anyhow::{bail, Result};
-use slog::Logger;
+use slog::{o, Logger};
 use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::io::Write;
-use std::os::raw::{c_char, c_int};
+use std::os::raw::c_char;
 use std::path::{Path, PathBuf};
-use std::process::{exit, Command};
+use std::process::exit;
 
-const PFEXEC: &str = "/bin/pfexec";
-const ZONEADM: &str = "/usr/sbin/zoneadm";
-const ZONECFG: &str = "/usr/sbin/zonecfg";
-const ZLOGIN: &str = "/usr/sbin/zlogin";
-const SVCS: &str = "/bin/svcs";
+use super::ensure;
+use super::platform::{self, clear_errno, errno};
+use super::tools::Tool;
 
 #[derive(Debug, PartialEq)]
 pub struct UserAttr {
@@ -34,69 +32,11 @@ impl UserAttr {
     }
 }
 
-#[repr(C)]
-struct Kv {
-    key: *const c_char,
-    value: *const c_char,
-}
-
-impl Kv {
-    fn name(&self) -> &CStr {
-        unsafe { CStr::from_ptr(self.key) }
-    }
-
-    fn value(&self) -> &CStr {
-        unsafe { CStr::from_ptr(self.value) }
-    }
-}
-
-#[repr(C)]
-struct Kva {
-    length: c_int,
-    data: *const Kv,
-}
-
-impl Kva {
-    fn values(&self) -> &[Kv] {
-        unsafe { std::slice::from_raw_parts(self.data, self.length as usize) }
-    }
-}
-
-#[repr(C)]
-struct UserAttrRaw {
-    name: *mut c_char,
-    qualifier: *mut c_char,
-    res1: *mut c_char,
-    res2: *mut c_char,
-    attr: *mut Kva,
-}
-
-#[link(name = "secdb")]
-extern "C" {
-    fn getusernam(buf: *const c_char) -> *mut UserAttrRaw;
-    fn free_userattr(userattr: *mut UserAttrRaw);
-}
-
 pub fn get_user_attr_by_name(name: &str) -> Result<Option<UserAttr>> {
-    let mut out = UserAttr { name: name.to_string(), attr: HashMap::new() };
+    let cname = CString::new(name.to_owned())?;
 
-    let name = CString::new(name.to_owned())?;
-    let ua = unsafe { getusernam(name.as_ptr()) };
-    if ua.is_null() {
-        return Ok(None);
-    }
-
-    for kv in unsafe { (*(*ua).attr).values() } {
-        if let (Ok(k), Ok(v)) = (kv.name().to_str(), kv.value().to_str()) {
-            out.attr.insert(k.to_string(), v.to_string());
-        } else {
-            continue;
-        }
-    }
-
-    unsafe { free_userattr(ua) };
-
-    Ok(Some(out))
+    Ok(platform::user_attr(&cname)
+        .map(|attr| UserAttr { name: name.to_string(), attr }))
 }
 
 pub fn nodename() -> String {
@@ -113,47 +53,12 @@ pub fn nodename() -> String {
     .to_string()
 }
 
-#[link(name = "c")]
-extern "C" {
-    fn getzoneid() -> i32;
-    fn getzonenamebyid(id: i32, buf: *mut u8, buflen: usize) -> isize;
-}
-
 pub fn zoneid() -> i32 {
-    unsafe { getzoneid() }
+    platform::zoneid()
 }
 
 pub fn zonename() -> String {
-    let buf = unsafe {
-        let mut buf: [u8; 64] = std::mem::zeroed(); /* ZONENAME_MAX */
-
-        let sz = getzonenamebyid(getzoneid(), buf.as_mut_ptr(), 64);
-        if !(0..=64).contains(&sz) {
-            eprintln!("getzonenamebyid failure");
-            exit(100);
-        }
-
-        Vec::from(&buf[0..sz as usize])
-    };
-    std::ffi::CStr::from_bytes_with_nul(&buf)
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string()
-}
-
-fn errno() -> i32 {
-    unsafe {
-        let enp = libc::___errno();
-        *enp
-    }
-}
-
-fn clear_errno() {
-    unsafe {
-        let enp = libc::___errno();
-        *enp = 0;
-    }
+    platform::zonename()
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -180,13 +85,15 @@ impl Passwd {
             }
         }
 
+        let (age, comment) = platform::pw_age_comment(unsafe { &*p });
+
         Ok(Passwd {
             name: cs(unsafe { (*p).pw_name })?,
             passwd: cs(unsafe { (*p).pw_passwd })?,
             uid: unsafe { (*p).pw_uid },
             gid: unsafe { (*p).pw_gid },
-            age: cs(unsafe { (*p).pw_age })?,
-            comment: cs(unsafe { (*p).pw_comment })?,
+            age: cs(age)?,
+            comment: cs(comment)?,
             gecos: cs(unsafe { (*p).pw_gecos })?,
             dir: cs(unsafe { (*p).pw_dir })?,
             shell: cs(unsafe { (*p).pw_shell })?,
@@ -406,11 +313,10 @@ impl ZonesExt for Vec<Zone> {
 }
 
 pub fn zone_list() -> Result<Vec<Zone>> {
-    let out = Command::new("/usr/sbin/zoneadm")
-        .env_clear()
-        .arg("list")
-        .arg("-cip")
-        .output()?;
+    let out = ensure::capture(
+        Tool::Zoneadm.command().env_clear().arg("list").arg("-cip"),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("zoneadm list failure: {}", out.info());
@@ -461,13 +367,16 @@ where
 
     println!("args: {}", script);
 
-    let out = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZONECFG)
-        .arg("-z")
-        .arg(n)
-        .arg(script)
-        .output()?;
+    let out = ensure::capture(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zonecfg.path())
+            .arg("-z")
+            .arg(n)
+            .arg(script),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("zonecfg create failure: {}", out.info());
@@ -497,13 +406,16 @@ where
 
     println!("args: {}", script);
 
-    let out = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZONECFG)
-        .arg("-z")
-        .arg(n)
-        .arg(script)
-        .output()?;
+    let out = ensure::capture(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zonecfg.path())
+            .arg("-z")
+            .arg(n)
+            .arg(script),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("zonecfg failure: {}", out.info());
@@ -518,9 +430,9 @@ where
 {
     let n = name.as_ref();
 
-    let mut cmd = Command::new(PFEXEC);
+    let mut cmd = Tool::Pfexec.command();
     cmd.env_clear();
-    cmd.arg(ZONEADM);
+    cmd.arg(Tool::Zoneadm.path());
     cmd.arg("-z");
     cmd.arg(n);
     cmd.arg("install");
@@ -532,9 +444,8 @@ where
         }
     }
 
-    let mut child = cmd.spawn()?;
-
-    let status = child.wait()?;
+    let log = Logger::root(slog::Discard, o!());
+    let status = ensure::status(&log, &mut cmd, ensure::Retry::NEVER)?;
 
     if !status.success() {
         bail!("zoneadm install failure");
@@ -551,17 +462,16 @@ where
     let n = name.as_ref();
     let src = src.as_ref();
 
-    let mut cmd = Command::new(PFEXEC);
+    let mut cmd = Tool::Pfexec.command();
     cmd.env_clear();
-    cmd.arg(ZONEADM);
+    cmd.arg(Tool::Zoneadm.path());
     cmd.arg("-z");
     cmd.arg(n);
     cmd.arg("clone");
     cmd.arg(src);
 
-    let mut child = cmd.spawn()?;
-
-    let status = child.wait()?;
+    let log = Logger::root(slog::Discard, o!());
+    let status = ensure::status(&log, &mut cmd, ensure::Retry::NEVER)?;
 
     if !status.success() {
         bail!("zoneadm clone failure");
@@ -576,13 +486,16 @@ where
 {
     let n = name.as_ref();
 
-    let out = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZONEADM)
-        .arg("-z")
-        .arg(n)
-        .arg("halt")
-        .output()?;
+    let out = ensure::capture(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zoneadm.path())
+            .arg("-z")
+            .arg(n)
+            .arg("halt"),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("zoneadm halt {} failure: {}", n, out.info());
@@ -597,13 +510,16 @@ where
 {
     let n = name.as_ref();
 
-    let out = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZONEADM)
-        .arg("-z")
-        .arg(n)
-        .arg("boot")
-        .output()?;
+    let out = ensure::capture(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zoneadm.path())
+            .arg("-z")
+            .arg(n)
+            .arg("boot"),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("zoneadm boot {} failure: {}", n, out.info());
@@ -613,7 +529,7 @@ where
 }
 
 pub fn zone_milestone_wait<S1, S2>(
-    _log: &Logger,
+    log: &Logger,
     name: S1,
     fmri: S2,
 ) -> Result<()>
@@ -625,15 +541,20 @@ where
     let fmri = fmri.as_ref();
 
     loop {
-        let out = Command::new(PFEXEC)
-            .env_clear()
-            .arg(SVCS)
-            .arg("-z")
-            .arg(name)
-            .arg("-Ho")
-            .arg("sta,nsta")
-            .arg(fmri)
-            .output();
+        let out = ensure::output(
+            log,
+            Tool::Pfexec
+                .command()
+                .env_clear()
+                .arg(Tool::Svcs.path())
+                .arg("-z")
+                .arg(name)
+                .arg("-Ho")
+                .arg("sta,nsta")
+                .arg(fmri),
+            ensure::Timeout::Short,
+            ensure::Retry::NEVER,
+        );
 
         if let Ok(out) = out {
             let stdout = String::from_utf8(out.stdout)?;
@@ -663,13 +584,16 @@ where
 {
     let n = name.as_ref();
 
-    let out = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZONEADM)
-        .arg("-z")
-        .arg(n)
-        .arg("mount")
-        .output()?;
+    let out = ensure::capture(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zoneadm.path())
+            .arg("-z")
+            .arg(n)
+            .arg("mount"),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("zoneadm mount {} failure: {}", n, out.info());
@@ -684,13 +608,16 @@ where
 {
     let n = name.as_ref();
 
-    let out = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZONEADM)
-        .arg("-z")
-        .arg(n)
-        .arg("unmount")
-        .output()?;
+    let out = ensure::capture(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zoneadm.path())
+            .arg("-z")
+            .arg(n)
+            .arg("unmount"),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("zoneadm unmount {} failure: {}", n, out.info());
@@ -705,14 +632,17 @@ where
 {
     let n = name.as_ref();
 
-    let out = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZONEADM)
-        .arg("-z")
-        .arg(n)
-        .arg("uninstall")
-        .arg("-F")
-        .output()?;
+    let out = ensure::capture(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zoneadm.path())
+            .arg("-z")
+            .arg(n)
+            .arg("uninstall")
+            .arg("-F"),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("zoneadm uninstall {} failure: {}", n, out.info());
@@ -727,14 +657,17 @@ where
 {
     let n = name.as_ref();
 
-    let out = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZONECFG)
-        .arg("-z")
-        .arg(n)
-        .arg("delete")
-        .arg("-F")
-        .output()?;
+    let out = ensure::capture(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zonecfg.path())
+            .arg("-z")
+            .arg(n)
+            .arg("delete")
+            .arg("-F"),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("zonecfg delete {} failure: {}", n, out.info());
@@ -752,15 +685,17 @@ where
     let c = contents.as_ref();
     let sp = format!("/tmp/helios.build.{}.sh", std::process::id());
 
-    let mut child = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZLOGIN)
-        .arg("-S")
-        .arg(n)
-        .arg("tee")
-        .arg(&sp)
-        .stdin(std::process::Stdio::piped())
-        .spawn()?;
+    let mut child = ensure::spawn(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zlogin.path())
+            .arg("-S")
+            .arg(n)
+            .arg("tee")
+            .arg(&sp)
+            .stdin(std::process::Stdio::piped()),
+    )?;
 
     {
         let mut stdin = child.stdin.take().unwrap();
@@ -774,15 +709,18 @@ where
         bail!("zlogin {} tee {} failure", n, sp);
     }
 
-    let out = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZLOGIN)
-        .arg("-S")
-        .arg(n)
-        .arg("/bin/chmod")
-        .arg("0755")
-        .arg(&sp)
-        .output()?;
+    let out = ensure::capture(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zlogin.path())
+            .arg("-S")
+            .arg(n)
+            .arg("/bin/chmod")
+            .arg("0755")
+            .arg(&sp),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("zlogin {} chmod {} failure: {}", n, sp, out.info());
@@ -801,16 +739,18 @@ where
     let p = path.as_ref();
     let l = line.as_ref();
 
-    let mut child = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZLOGIN)
-        .arg("-S")
-        .arg(n)
-        .arg("tee")
-        .arg("-a")
-        .arg(p)
-        .stdin(std::process::Stdio::piped())
-        .spawn()?;
+    let mut child = ensure::spawn(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zlogin.path())
+            .arg("-S")
+            .arg(n)
+            .arg("tee")
+            .arg("-a")
+            .arg(p)
+            .stdin(std::process::Stdio::piped()),
+    )?;
 
     {
         let mut stdin = child.stdin.take().unwrap();
@@ -840,29 +780,35 @@ where
     let n = name.as_ref();
     let p = path.as_ref();
 
-    let out = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZLOGIN)
-        .arg("-S")
-        .arg(n)
-        .arg("mkdir")
-        .arg("-p")
-        .arg(p)
-        .output()?;
+    let out = ensure::capture(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zlogin.path())
+            .arg("-S")
+            .arg(n)
+            .arg("mkdir")
+            .arg("-p")
+            .arg(p),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("zlogin {} mkdir {} failure: {}", n, p.display(), out.info());
     }
 
-    let out = Command::new(PFEXEC)
-        .env_clear()
-        .arg(ZLOGIN)
-        .arg("-S")
-        .arg(n)
-        .arg("chown")
-        .arg(format!("{}:{}", uid, gid))
-        .arg(p)
-        .output()?;
+    let out = ensure::capture(
+        Tool::Pfexec
+            .command()
+            .env_clear()
+            .arg(Tool::Zlogin.path())
+            .arg("-S")
+            .arg(n)
+            .arg("chown")
+            .arg(format!("{}:{}", uid, gid))
+            .arg(p),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("zlogin {} chown {} failure: {}", n, p.display(), out.info());
@@ -870,3 +816,22 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn net_adm_parse() {
+        let out =
+            b"global:0:/::shared\nbuild:3:/zones/build:a\\:b:excl\n".to_vec();
+        assert_eq!(
+            parse_net_adm(out).unwrap(),
+            vec![
+                vec!["global", "0", "/", "", "shared"],
+                vec!["build", "3", "/zones/build", "a:b", "excl"],
+            ]
+        );
+        assert!(!zonename().is_empty());
+    }
+}
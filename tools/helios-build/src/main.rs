@@ -10,28 +10,36 @@ use helios_build_utils::metadata::{self, ArchiveType};
 use helios_build_utils::tree;
 use serde::Deserialize;
 use slog::Logger;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufReader, Read, Write};
+use std::io::{BufReader, IsTerminal, Read, Seek, Write};
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::process::CommandExt;
 use std::path::Path;
 use std::process::Command;
-use std::time::{Instant, SystemTime};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use time::{format_description, OffsetDateTime};
 use walkdir::WalkDir;
 
 mod archive;
+mod bootimage;
+mod compress;
 pub mod ensure;
 mod expand;
 pub mod illumos;
+mod platform;
+mod report;
+mod sign;
+mod sparse;
+#[cfg(test)]
+mod testutil;
+mod tools;
+mod transcript;
 mod zfs;
 
 use expand::Expansion;
-
-const PKGREPO: &str = "/usr/bin/pkgrepo";
-const PKGRECV: &str = "/usr/bin/pkgrecv";
-const PKGDEPOTD: &str = "/usr/lib/pkg.depotd";
+use tools::Tool;
 
 const DASHREV: u32 = 0;
 
@@ -99,6 +107,308 @@ impl RelVer {
 const DATE_FORMAT_STR: &str = "[year]-[month]-[day]";
 const TIME_FORMAT_STR: &str = "[hour]:[minute]:[second]";
 
+struct NameToken {
+    name: &'static str,
+    example: &'static str,
+    /**
+     * Whether the token may be unset, in which case the template must be
+     * written to handle that; e.g., "${debug?-debug}".
+     */
+    optional: bool,
+    desc: &'static str,
+}
+
+/*
+ * The tokens that may be used in the image name template (-N).  The example
+ * values are used to check the template before the build begins, and are
+ * listed for any token whose value cannot be determined until the image has
+ * been built.  Where the value is already known when the build begins, as for
+ * the flags and environment variables, the check uses that instead.
+ */
+const NAME_TOKENS: &[NameToken] = &[
+    NameToken {
+        name: "user",
+        example: "builder",
+        optional: false,
+        desc: "user name of the person running the build",
+    },
+    NameToken {
+        name: "host",
+        example: "buildhost",
+        optional: false,
+        desc: "node name of the build machine",
+    },
+    NameToken {
+        name: "date",
+        example: "2024-03-19",
+        optional: false,
+        desc: "date of the build, as YYYY-MM-DD",
+    },
+    NameToken {
+        name: "time",
+        example: "21:01:16",
+        optional: false,
+        desc: "time of the build, as HH:MM:SS",
+    },
+    NameToken {
+        name: "os_short_commit",
+        example: "49fb31d",
+        optional: false,
+        desc:
+            "short illumos commit hash, from /etc/versions/build in the image",
+    },
+    NameToken {
+        name: "relver",
+        example: "3",
+        optional: false,
+        desc: "Helios release version",
+    },
+    NameToken {
+        name: "debug",
+        example: "debug",
+        optional: true,
+        desc: "\"debug\" if DEBUG packages are used (-d), otherwise unset",
+    },
+    NameToken {
+        name: "recovery",
+        example: "recovery",
+        optional: true,
+        desc: "\"recovery\" for a recovery image (-R), otherwise unset",
+    },
+    NameToken {
+        name: "brand",
+        example: "brand",
+        optional: true,
+        desc:
+            "\"brand\" if the omicron1 brand is included (-B), otherwise unset",
+    },
+    NameToken {
+        name: "boards",
+        example: "cosmo,gimlet",
+        optional: false,
+        desc: "comma-separated list of the selected boards",
+    },
+    NameToken {
+        name: "build_number",
+        example: "42",
+        optional: false,
+        desc: "local build number, incremented by each build that uses it",
+    },
+    NameToken {
+        name: "git.PROJECT.short",
+        example: "49fb31d",
+        optional: false,
+        desc: "short commit hash of a project in projects/, or of the \
+            illumos gate in use (\"git.illumos.short\")",
+    },
+    NameToken {
+        name: "git.PROJECT.branch",
+        example: "master",
+        optional: false,
+        desc: "checked out branch of a project",
+    },
+    NameToken {
+        name: "git.PROJECT.dirty",
+        example: "dirty",
+        optional: true,
+        desc: "\"dirty\" if a project has local changes, otherwise unset",
+    },
+    NameToken {
+        name: "env.VARIABLE",
+        example: "1234",
+        optional: true,
+        desc: "value of an environment variable from NAME_ENV_VARS, if set",
+    },
+];
+
+/*
+ * The environment variables that may be included in an image name.  These are
+ * limited to those that identify a build, so that a template cannot be used to
+ * expose arbitrary parts of the environment.
+ */
+const NAME_ENV_VARS: &[&str] = &[
+    "BUILD_ID",
+    "BUILD_NUMBER",
+    "BUILD_TAG",
+    "CI",
+    "CI_JOB_ID",
+    "GITHUB_RUN_ID",
+    "GITHUB_RUN_NUMBER",
+    "GITHUB_SHA",
+];
+
+/**
+ * Find the image name token that matches a variable name.  In a token name,
+ * an upper case component such as "PROJECT" matches any component.
+ */
+fn name_token(v: &str) -> Option<&'static NameToken> {
+    NAME_TOKENS.iter().find(|t| {
+        let p = t.name.split('.').collect::<Vec<_>>();
+        let n = v.split('.').collect::<Vec<_>>();
+
+        p.len() == n.len()
+            && p.iter().zip(n.iter()).all(|(p, n)| {
+                p == n
+                    || (!n.is_empty()
+                        && p.chars().all(|c| c.is_ascii_uppercase()))
+            })
+    })
+}
+
+/**
+ * Return the names of the projects for which git information is used in an
+ * image name.
+ */
+fn name_git_projects(exp: &Expansion) -> BTreeSet<String> {
+    exp.variables()
+        .into_iter()
+        .filter_map(|v| v.strip_prefix("git."))
+        .filter_map(|v| v.split_once('.'))
+        .map(|(project, _)| project.to_string())
+        .collect()
+}
+
+/**
+ * Increment and return the local build number, which is stored in the tmp
+ * directory.  The file is locked while it is updated, so that concurrent
+ * builds receive distinct numbers.
+ */
+fn next_build_number(log: &Logger) -> Result<u64> {
+    ensure_dir(log, &["tmp"])?;
+    let p = top_path(&["tmp", "build-number"])?;
+    let mut f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&p)?;
+
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_type = libc::F_WRLCK as libc::c_short;
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    if unsafe { libc::fcntl(f.as_raw_fd(), libc::F_SETLKW, &fl) } != 0 {
+        let e = std::io::Error::last_os_error();
+        bail!("could not lock {p:?}: {e}");
+    }
+
+    let mut s = String::new();
+    f.read_to_string(&mut s)?;
+    let n = if s.trim().is_empty() {
+        1
+    } else {
+        s.trim()
+            .parse::<u64>()
+            .with_context(|| format!("invalid build number in {p:?}"))?
+            + 1
+    };
+
+    f.seek(std::io::SeekFrom::Start(0))?;
+    f.set_len(0)?;
+    f.write_all(format!("{n}\n").as_bytes())?;
+    f.sync_all()?;
+
+    Ok(n)
+}
+
+/**
+ * Determine the values of those image name tokens that do not depend on the
+ * contents of the image.
+ */
+fn name_tokens(now: OffsetDateTime) -> Result<HashMap<String, String>> {
+    let mut tokens = HashMap::new();
+
+    tokens.insert(
+        "user".to_string(),
+        illumos::get_username()?.unwrap_or_else(|| "unknown".to_string()),
+    );
+    tokens.insert("host".to_string(), illumos::nodename());
+    let dt_fmt = format_description::parse(DATE_FORMAT_STR).unwrap();
+    tokens.insert("date".to_string(), now.format(&dt_fmt).unwrap());
+    let dt_fmt = format_description::parse(TIME_FORMAT_STR).unwrap();
+    tokens.insert("time".to_string(), now.format(&dt_fmt).unwrap());
+
+    Ok(tokens)
+}
+
+/**
+ * Determine the values of those image name tokens that come from the command
+ * line flags and the environment.  Tokens that are not set are left out.
+ */
+fn option_name_tokens(
+    debug: bool,
+    recovery: bool,
+    brand: bool,
+) -> HashMap<String, String> {
+    let mut tokens = HashMap::new();
+
+    for (flag, set) in
+        [("debug", debug), ("recovery", recovery), ("brand", brand)]
+    {
+        if set {
+            tokens.insert(flag.to_string(), flag.to_string());
+        }
+    }
+    for var in NAME_ENV_VARS {
+        if let Ok(val) = std::env::var(var) {
+            tokens.insert(format!("env.{var}"), val);
+        }
+    }
+
+    tokens
+}
+
+/**
+ * Check that an image name template refers only to tokens that exist, and
+ * that it can be evaluated, so that mistakes are caught before the build
+ * rather than at the end of it.  Tokens with a value in "known" are checked
+ * with that value, and any other optional token must work whether it is set
+ * or not.
+ */
+fn check_name_template(
+    template: &str,
+    known: &HashMap<String, String>,
+) -> Result<Expansion> {
+    let exp = Expansion::parse(template)
+        .with_context(|| format!("invalid image name {template:?}"))?;
+
+    let mut set = HashMap::new();
+    let mut unset = HashMap::new();
+    for v in exp.variables() {
+        let Some(t) = name_token(v) else {
+            bail!(
+                "image name {template:?} uses unknown token {v:?} \
+                (see --list-name-tokens)"
+            );
+        };
+        if let Some(var) = v.strip_prefix("env.") {
+            if !NAME_ENV_VARS.contains(&var) {
+                bail!(
+                    "environment variable {var:?} may not be used in an \
+                    image name; allowed variables are: {}",
+                    NAME_ENV_VARS.join(", "),
+                );
+            }
+        }
+
+        if let Some(val) = known.get(v) {
+            set.insert(v.to_string(), val.to_string());
+            unset.insert(v.to_string(), val.to_string());
+        } else {
+            set.insert(v.to_string(), t.example.to_string());
+            if !t.optional {
+                unset.insert(v.to_string(), t.example.to_string());
+            }
+        }
+    }
+
+    for vars in [set, unset] {
+        exp.evaluate(&vars)
+            .with_context(|| format!("invalid image name {template:?}"))?;
+    }
+
+    Ok(exp)
+}
+
 fn baseopts() -> getopts::Options {
     let mut opts = getopts::Options::new();
 
@@ -119,10 +429,30 @@ fn pc(s: &str) -> Component<'_> {
     Component::Normal(OsStr::new(s))
 }
 
+#[cfg(test)]
+thread_local! {
+    /*
+     * Tests supply a scratch directory to stand in for the repository, which
+     * also holds the release file that would otherwise come from /etc.
+     */
+    static TEST_TOP: std::cell::RefCell<Option<PathBuf>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+#[cfg(test)]
+fn test_top() -> Option<PathBuf> {
+    TEST_TOP.with(|t| t.borrow().clone())
+}
+
 /**
  * Determine the location of the top-level helios.git clone.
  */
 fn top() -> Result<PathBuf> {
+    #[cfg(test)]
+    if let Some(top) = test_top() {
+        return Ok(top);
+    }
+
     /*
      * Start with the path of the current executable, and discard the last
      * component of that path (the executable file itself) so that we can reason
@@ -295,11 +625,9 @@ impl Project {
     }
 }
 
-fn ensure_dir(components: &[&str]) -> Result<PathBuf> {
+fn ensure_dir(log: &Logger, components: &[&str]) -> Result<PathBuf> {
     let dir = top_path(components)?;
-    if !exists_dir(&dir)? {
-        std::fs::create_dir(&dir)?;
-    }
+    ensure::directory(log, &dir, 0o755, None, None)?;
     Ok(dir)
 }
 
@@ -322,12 +650,29 @@ where
             info!(log, "repository {} exists, skipping creation", paths);
             return Ok(());
         }
+        if ensure::dry_run() {
+            info!(log, "dry run: would recreate repository {}", paths);
+            return Ok(());
+        }
         info!(log, "repository {} exists, removing first", paths);
         std::fs::remove_dir_all(path)?;
+    } else if ensure::dry_run() {
+        info!(log, "dry run: would create repository {}", paths);
+        return Ok(());
     }
 
-    ensure::run(log, &[PKGREPO, "create", paths])?;
-    ensure::run(log, &[PKGREPO, "add-publisher", "-s", paths, publ])?;
+    ensure::run(
+        log,
+        &[Tool::Pkgrepo.path().as_str(), "create", paths],
+        ensure::Timeout::Short,
+        ensure::Retry::NEVER,
+    )?;
+    ensure::run(
+        log,
+        &[Tool::Pkgrepo.path().as_str(), "add-publisher", "-s", paths, publ],
+        ensure::Timeout::Short,
+        ensure::Retry::NEVER,
+    )?;
 
     info!(log, "repository {} for publisher {} created", paths, publ);
 
@@ -401,7 +746,7 @@ fn cmd_merge_illumos(ca: &CommandArg) -> Result<()> {
         "illumos".to_string()
     };
 
-    ensure_dir(&["tmp", &tillumos])?;
+    ensure_dir(log, &["tmp", &tillumos])?;
     let repo_merge = top_path(&["tmp", &tillumos, "nightly-merged"])?;
 
     let repo_d =
@@ -421,7 +766,7 @@ fn cmd_merge_illumos(ca: &CommandArg) -> Result<()> {
     ensure::run(
         log,
         &[
-            "/usr/bin/pkgmerge",
+            Tool::Pkgmerge.path().as_str(),
             "-d",
             repo_merge.to_str().unwrap(),
             "-s",
@@ -429,6 +774,8 @@ fn cmd_merge_illumos(ca: &CommandArg) -> Result<()> {
             "-s",
             &format!("debug.illumos=true,{}/", repo_d.to_str().unwrap()),
         ],
+        ensure::Timeout::Build,
+        ensure::Retry::NEVER,
     )?;
 
     info!(log, "transforming packages for publishing...");
@@ -454,7 +801,7 @@ fn cmd_merge_illumos(ca: &CommandArg) -> Result<()> {
     ensure::run(
         log,
         &[
-            PKGRECV,
+            Tool::Pkgrecv.path().as_str(),
             "-s",
             repo_merge.to_str().unwrap(),
             "-d",
@@ -469,8 +816,20 @@ fn cmd_merge_illumos(ca: &CommandArg) -> Result<()> {
             "latest",
             "*",
         ],
+        ensure::Timeout::Build,
+        ensure::Retry::NEVER,
+    )?;
+    ensure::run(
+        log,
+        &[
+            Tool::Pkgrepo.path().as_str(),
+            "refresh",
+            "-s",
+            repo.to_str().unwrap(),
+        ],
+        ensure::Timeout::Short,
+        ensure::Retry::NEVER,
     )?;
-    ensure::run(log, &[PKGREPO, "refresh", "-s", repo.to_str().unwrap()])?;
 
     /*
      * Clean up the temporary merged repo files:
@@ -487,8 +846,10 @@ fn ncpus() -> Result<u32> {
     /*
      * XXX Replace with kstat check.
      */
-    let out =
-        Command::new("/usr/sbin/psrinfo").env_clear().arg("-t").output()?;
+    let out = ensure::capture(
+        Tool::Psrinfo.command().env_clear().arg("-t"),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("could not count CPUs: {}", out.info());
@@ -543,13 +904,30 @@ fn regen_publisher_mog<P: AsRef<Path>>(
     } else {
         top_path(&["packages", "publisher.mogrify"])?
     };
-    ensure::file_str(log, &mog, &mogpath, 0o644, ensure::Create::Always)?;
+    ensure::file_str(
+        log,
+        &mog,
+        &mogpath,
+        0o644,
+        None,
+        None,
+        ensure::Create::Always,
+    )?;
     Ok(())
 }
 
+fn os_release_path() -> PathBuf {
+    #[cfg(test)]
+    if let Some(top) = test_top() {
+        return top.join("os-release");
+    }
+
+    PathBuf::from("/etc/os-release")
+}
+
 fn determine_release_version() -> Result<RelVer> {
-    let relpath = "/etc/os-release";
-    let relfile = std::fs::read_to_string(relpath)?;
+    let relpath = os_release_path();
+    let relfile = std::fs::read_to_string(&relpath)?;
     let map: HashMap<_, _> =
         relfile.lines().filter_map(|l| l.split_once('=')).collect();
 
@@ -720,7 +1098,15 @@ fn regen_illumos_sh<P: AsRef<Path>>(
     env += "export ON_CLOSED_BINS=/opt/onbld/closed\n";
     env += &format!("export PKGVERS_BRANCH='{pkgvers}'\n");
 
-    ensure::file_str(log, &env, &path_env, 0o644, ensure::Create::Always)?;
+    ensure::file_str(
+        log,
+        &env,
+        &path_env,
+        0o644,
+        None,
+        None,
+        ensure::Create::Always,
+    )?;
 
     Ok(path_env)
 }
@@ -798,7 +1184,12 @@ fn cmd_build_illumos(ca: &CommandArg) -> Result<()> {
         env_sh.to_str().unwrap()
     );
 
-    ensure::run(log, &["/sbin/sh", "-c", &script])?;
+    ensure::run(
+        log,
+        &[Tool::Sh.path().as_str(), "-c", &script],
+        ensure::Timeout::Never,
+        ensure::Retry::NEVER,
+    )?;
 
     Ok(())
 }
@@ -826,7 +1217,7 @@ fn create_transformed_repo(
     ensure::run(
         log,
         &[
-            PKGRECV,
+            Tool::Pkgrecv.path().as_str(),
             "-s",
             repo_nd.to_str().unwrap(),
             "-d",
@@ -839,9 +1230,21 @@ fn create_transformed_repo(
             "latest",
             "*",
         ],
+        ensure::Timeout::Build,
+        ensure::Retry::NEVER,
     )?;
     if refresh {
-        ensure::run(log, &[PKGREPO, "refresh", "-s", repo.to_str().unwrap()])?;
+        ensure::run(
+            log,
+            &[
+                Tool::Pkgrepo.path().as_str(),
+                "refresh",
+                "-s",
+                repo.to_str().unwrap(),
+            ],
+            ensure::Timeout::Short,
+            ensure::Retry::NEVER,
+        )?;
     }
 
     Ok(repo)
@@ -924,7 +1327,7 @@ fn cmd_illumos_onu(ca: &CommandArg) -> Result<()> {
     let repo = create_transformed_repo(
         log,
         &gate,
-        &ensure_dir(&["tmp", &tonu])?,
+        &ensure_dir(log, &["tmp", &tonu])?,
         res.opt_present("d"),
         true,
     )?;
@@ -964,17 +1367,18 @@ fn cmd_illumos_onu(ca: &CommandArg) -> Result<()> {
         /*
          * Run a pkg.depotd to serve the packages we have just transformed.
          */
-        ensure_dir(&["tmp", &tdepot])?;
-        let logdir = ensure_dir(&["tmp", &tdepot, "log"])?;
+        ensure_dir(log, &["tmp", &tdepot])?;
+        let logdir = ensure_dir(log, &["tmp", &tdepot, "log"])?;
         let mut access = logdir.clone();
         access.push("access");
-        let rootdir = ensure_dir(&["tmp", &tdepot, "root"])?;
+        let rootdir = ensure_dir(log, &["tmp", &tdepot, "root"])?;
 
         info!(log, "access log file is {:?}", &access);
         info!(log, "listening on port {}", port);
         info!(log, "^C to quit");
 
-        return Err(Command::new(PKGDEPOTD)
+        return Err(Tool::Pkgdepotd
+            .command()
             /*
              * Setting this environment variable prevents the depot from
              * daemonising.
@@ -1028,7 +1432,7 @@ fn cmd_illumos_onu(ca: &CommandArg) -> Result<()> {
     ensure::run(
         log,
         &[
-            "pfexec",
+            Tool::Pfexec.path().as_str(),
             onu.to_str().unwrap(),
             "-v",
             "-d",
@@ -1036,6 +1440,8 @@ fn cmd_illumos_onu(ca: &CommandArg) -> Result<()> {
             "-t",
             &bename,
         ],
+        ensure::Timeout::Build,
+        ensure::Retry::NEVER,
     )?;
 
     info!(log, "onu complete!  you must now reboot");
@@ -1388,9 +1794,19 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
     opts.optmulti("F", "", "pass extra image builder features", "KEY[=VAL]");
     opts.optflag("B", "", "include omicron1 brand");
     opts.optopt("N", "name", "image name", "NAME");
+    opts.optflag(
+        "",
+        "list-name-tokens",
+        "list the tokens that may be used in the image name, and exit",
+    );
     opts.optflag("R", "", "recovery image");
     opts.optmulti("X", "", "skip this phase", "PHASE");
     opts.optflag("", "ddr-testing", "build ROMs for other DDR frequencies");
+    opts.optflag(
+        "",
+        "rom-archives",
+        "also create a separate archive for each board, containing only the ROM",
+    );
     opts.optmulti(
         "p",
         "",
@@ -1399,6 +1815,37 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
     );
     opts.optopt("P", "", "include all files from extra proto area", "DIR");
     opts.optopt("S", "", "ramdisk target size", "GIGABYTES");
+    opts.optflag(
+        "",
+        "reproducible",
+        "use the illumos commit time, rather than the current time, for \
+        timestamps in the image archive",
+    );
+    opts.optopt(
+        "",
+        "compression",
+        "compress the image archive with gzip, zstd, or xz, at an optional \
+        level (default: gzip:9)",
+        "CODEC[:LEVEL]",
+    );
+    opts.optopt(
+        "",
+        "compression-threads",
+        "compress the image archive in independent blocks on this many \
+        threads (default: 1)",
+        "THREADS",
+    );
+    opts.optflag(
+        "",
+        "sparse",
+        "store runs of zeroes in the boot image as holes in the image archive",
+    );
+    opts.optopt(
+        "",
+        "sign-key",
+        "sign the image archive with the Ed25519 private key in this file",
+        "KEYFILE",
+    );
 
     let group = "sled";
 
@@ -1434,8 +1881,31 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
     };
 
     let ddr_testing = res.opt_present("ddr-testing");
+    let rom_archives = res.opt_present("rom-archives");
     let skips = res.opt_strs("X");
     let recovery = res.opt_present("R");
+    let reproducible = res.opt_present("reproducible");
+    let sparse = res.opt_present("sparse");
+
+    let mut compression = if let Some(spec) = res.opt_str("compression") {
+        compress::Compression::parse(&spec)?
+    } else {
+        compress::Compression::default()
+    };
+    if let Some(threads) = res.opt_str("compression-threads") {
+        compression.threads = threads.parse().with_context(|| {
+            format!("invalid --compression-threads value {threads:?}")
+        })?;
+        if compression.threads == 0 {
+            bail!("--compression-threads must be at least 1");
+        }
+    }
+
+    let sign_key = if let Some(p) = res.opt_str("sign-key") {
+        Some(sign::load_signing_key(&PathBuf::from(p))?)
+    } else {
+        None
+    };
 
     let extra_proto = if let Some(dir) = res.opt_str("P") {
         let dir = PathBuf::from(dir);
@@ -1456,20 +1926,47 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
         return Ok(());
     }
 
+    if res.opt_present("list-name-tokens") {
+        let now = if let Some(t) = source_date_epoch()? {
+            OffsetDateTime::from_unix_timestamp(t.try_into()?)?
+        } else {
+            SystemTime::now().into()
+        };
+        let tokens = name_tokens(now)?;
+
+        println!("{:<20} {:<24} DESCRIPTION", "TOKEN", "EXAMPLE");
+        for t in NAME_TOKENS {
+            let example = tokens.get(t.name).map(|s| s.as_str());
+            println!(
+                "{:<20} {:<24} {}",
+                t.name,
+                example.unwrap_or(t.example),
+                t.desc,
+            );
+        }
+        println!();
+        println!("NAME_ENV_VARS: {}", NAME_ENV_VARS.join(", "));
+        return Ok(());
+    }
+
     if !res.free.is_empty() {
         bail!("unexpected arguments");
     }
 
-    let boards: HashMap<String, Board> =
+    let option_tokens =
+        option_name_tokens(res.opt_present("d"), recovery, brand);
+    let image_expansion = check_name_template(&image_template, &option_tokens)?;
+
+    let boards: BTreeMap<String, Board> =
         read_toml(top_path(&["image", "templates", group, "targets.toml"])?)?;
 
-    let target_boards: HashMap<String, Board> =
+    let target_boards: BTreeMap<String, Board> =
         if let Some(board) = res.opt_str("b") {
             let board_val = boards.get(&board).ok_or_else(|| {
                 anyhow!("Unknown board name ({board}) specified.")
             })?;
 
-            let mut filtered = HashMap::new();
+            let mut filtered = BTreeMap::new();
             filtered.insert(board, board_val.clone());
             filtered
         } else {
@@ -1529,6 +2026,48 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
         top_path(&["projects", "illumos"])?
     };
 
+    /*
+     * Make sure that any project named in the image name template exists.
+     * The "illumos" project is the gate we are building from, which may have
+     * been specified with -g.
+     */
+    let mut name_git_dirs = BTreeMap::new();
+    for project in name_git_projects(&image_expansion) {
+        let dir = if project == "illumos" {
+            gate.clone()
+        } else {
+            top_path(&["projects", &project])?
+        };
+        if !dir.is_dir() {
+            bail!("image name uses git information for unknown {project:?}");
+        }
+        name_git_dirs.insert(project, dir);
+    }
+
+    /*
+     * If a timestamp for the build has been provided in the environment, use
+     * it for the image name and the archive.  Otherwise, if a reproducible
+     * archive was requested, use the commit time of the illumos bits we are
+     * about to install.  This check is cheap, so do it before any expensive
+     * work.
+     */
+    let build_time = if let Some(t) = source_date_epoch()? {
+        info!(log, "using SOURCE_DATE_EPOCH {t} for timestamps");
+        Some(t)
+    } else if reproducible {
+        if !local_build {
+            bail!(
+                "--reproducible with external package repositories (-p) \
+                requires SOURCE_DATE_EPOCH in the environment"
+            );
+        }
+        let t = git_commit_time(&gate, "HEAD")?;
+        info!(log, "using illumos commit time {t} for timestamps");
+        Some(t)
+    } else {
+        None
+    };
+
     /*
      * We want a temporary directory name that does not overlap with other
      * concurrent usage of this tool.
@@ -1550,7 +2089,7 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
         "image".to_string()
     };
 
-    let tempdir = ensure_dir(&["tmp", &timage])?;
+    let tempdir = ensure_dir(log, &["tmp", &timage])?;
 
     let genproto = {
         let p = rel_path(Some(&tempdir), &["genproto.json"])?;
@@ -1636,7 +2175,7 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
     let features = res.opt_strs("F").into_iter().collect::<HashSet<_>>();
     std::fs::create_dir_all(&brand_extras)?;
     let basecmd = || -> Command {
-        let mut cmd = Command::new("pfexec");
+        let mut cmd = Tool::Pfexec.command();
         cmd.arg(&builder);
         cmd.arg("build");
         cmd.arg("-d").arg(&imgds);
@@ -1682,7 +2221,16 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
         let mut cmd = basecmd();
         cmd.arg("-n").arg("ramdisk-01-os");
         cmd.arg("--fullreset");
-        ensure::run2(log, &mut cmd)?;
+        /*
+         * This step installs packages from the publishers.  As it begins with
+         * a full reset, it can be repeated if the network lets us down.
+         */
+        ensure::run2(
+            log,
+            &mut cmd,
+            ensure::Timeout::Build,
+            ensure::Retry::NETWORK,
+        )?;
 
         if brand {
             /*
@@ -1696,19 +2244,31 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
             ensure::run(
                 log,
                 &[baseline, "-R", &root, brand_extras.to_str().unwrap()],
+                ensure::Timeout::Build,
+                ensure::Retry::NEVER,
             )?;
         }
 
         info!(log, "image builder template: ramdisk-02-trim...");
         let mut cmd = basecmd();
         cmd.arg("-n").arg("ramdisk-02-trim");
-        ensure::run2(log, &mut cmd)?;
+        ensure::run2(
+            log,
+            &mut cmd,
+            ensure::Timeout::Build,
+            ensure::Retry::NEVER,
+        )?;
 
         if recovery {
             info!(log, "image builder template: ramdisk-03-recovery-trim...");
             let mut cmd = basecmd();
             cmd.arg("-n").arg("ramdisk-03-recovery-trim");
-            ensure::run2(log, &mut cmd)?;
+            ensure::run2(
+                log,
+                &mut cmd,
+                ensure::Timeout::Build,
+                ensure::Retry::NEVER,
+            )?;
         }
     } else {
         info!(log, "skipping installation phase, using existing archive");
@@ -1718,23 +2278,17 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
     info!(log, "image builder template: {}...", tname);
     let mut cmd = basecmd();
     cmd.arg("-n").arg(tname);
-    ensure::run2(log, &mut cmd)?;
+    ensure::run2(log, &mut cmd, ensure::Timeout::Build, ensure::Retry::NEVER)?;
 
     /*
      * Build up the tokens that can be used in the image name.
      */
-    let mut tokens = HashMap::new();
-    let now: OffsetDateTime = SystemTime::now().into();
-
-    tokens.insert(
-        "user".to_string(),
-        illumos::get_username()?.unwrap_or_else(|| "unknown".to_string()),
-    );
-    tokens.insert("host".to_string(), illumos::nodename());
-    let dt_fmt = format_description::parse(DATE_FORMAT_STR).unwrap();
-    tokens.insert("date".to_string(), now.format(&dt_fmt).unwrap());
-    let dt_fmt = format_description::parse(TIME_FORMAT_STR).unwrap();
-    tokens.insert("time".to_string(), now.format(&dt_fmt).unwrap());
+    let now: OffsetDateTime = if let Some(t) = build_time {
+        OffsetDateTime::from_unix_timestamp(t.try_into()?)?
+    } else {
+        SystemTime::now().into()
+    };
+    let mut tokens = name_tokens(now)?;
 
     let buildfile: PathBuf =
         [&root, "etc", "versions", "build"].iter().collect();
@@ -1747,8 +2301,28 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
     };
 
     tokens.insert("os_short_commit".to_string(), hash);
+    tokens.insert("relver".to_string(), relver.to_string());
+    tokens.extend(option_tokens);
+    tokens.insert(
+        "boards".to_string(),
+        target_boards.keys().cloned().collect::<Vec<_>>().join(","),
+    );
+    for (project, dir) in name_git_dirs.iter() {
+        let bs = git_branch_status(dir)?;
+        let short = bs.oid.chars().take(7).collect::<String>();
+        tokens.insert(format!("git.{project}.short"), short);
+        tokens.insert(format!("git.{project}.branch"), bs.head);
+        if bs.changes > 0 {
+            tokens.insert(format!("git.{project}.dirty"), "dirty".to_string());
+        }
+    }
+    if image_expansion.variables().contains("build_number") {
+        let n = next_build_number(log)?;
+        info!(log, "local build number is {n}");
+        tokens.insert("build_number".to_string(), n.to_string());
+    }
 
-    let image_name = Expansion::parse(&image_template)?.evaluate(&tokens)?;
+    let image_name = image_expansion.evaluate(&tokens)?;
     info!(log, "expanded image name: {:?} -> {:?}", image_template, image_name);
 
     let raw = format!("{mp}/output/{group}-{tname}.raw");
@@ -1788,25 +2362,37 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
      */
     {
         let projdir = top_path(&["projects"])?;
-        let mut wd = std::fs::read_dir(&projdir)?;
 
-        while let Some(ent) = wd.next().transpose()? {
-            let dir = ent.path();
+        /*
+         * Visit the projects in name order so that the files appear in the
+         * archive in a stable order.
+         */
+        let mut dirs = std::fs::read_dir(&projdir)?
+            .map(|ent| Ok(ent?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        dirs.sort();
+
+        for dir in dirs {
             if !dir.is_dir() {
-                bail!("unexpected item in project area: {:?}", ent.path());
+                bail!("unexpected item in project area: {:?}", dir);
             }
             let name = dir.file_name().unwrap().to_str().unwrap().to_string();
 
             info!(log, "collecting git info from project {name:?}...");
 
-            let mut cmd = Command::new("git");
+            let mut cmd = Tool::Git.command();
             cmd.env_clear();
             cmd.arg("status");
             cmd.arg("-b");
             cmd.arg("--porcelain=2");
             cmd.current_dir(&dir);
 
-            let out = cmd.output()?;
+            let out = ensure::output(
+                log,
+                &mut cmd,
+                ensure::Timeout::Short,
+                ensure::Retry::NEVER,
+            )?;
             if !out.status.success() {
                 bail!("could not git status in {:?}: {}", dir, out.info());
             }
@@ -1836,15 +2422,20 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
     for (name, args) in pkg_infos {
         info!(log, "collecting packaging info {name:?}: {args:?}...");
 
-        let mut cmd = Command::new("pfexec");
+        let mut cmd = Tool::Pfexec.command();
         cmd.env_clear();
-        cmd.arg("pkg");
+        cmd.arg(Tool::Pkg.path());
         cmd.arg("-R").arg(&snapdir);
         for a in args {
             cmd.arg(a);
         }
 
-        let out = cmd.output()?;
+        let out = ensure::output(
+            log,
+            &mut cmd,
+            ensure::Timeout::Short,
+            ensure::Retry::NEVER,
+        )?;
         if !out.status.success() {
             bail!("could not run {args:?} into {name:?}: {}", out.info());
         }
@@ -1886,7 +2477,7 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
     if recovery {
         cmd.arg("-z");
     }
-    ensure::run2(log, &mut cmd)?;
+    ensure::run2(log, &mut cmd, ensure::Timeout::Build, ensure::Retry::NEVER)?;
 
     /*
      * Read the image checksum back in from the file that was built for
@@ -1903,20 +2494,34 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
      * Begin creating the archive now so that the archiver worker thread can
      * begin compressing it while we are doing other things.
      */
-    let tarpath = rel_path(Some(&outdir), &["os.tar.gz"])?;
-    let tar = archive::Archive::new(
-        &tarpath,
-        metadata::MetadataBuilder::new(ArchiveType::Os)
-            .info("name", &image_name)?
-            .info("checksum", &csum)?
-            .build()?,
-    )?;
+    let tarpath =
+        rel_path(Some(&outdir), &[&format!("os.{}", compression.suffix())])?;
+    let mtime = if let Some(t) = build_time {
+        t
+    } else {
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+    };
+    let mut md = metadata::MetadataBuilder::new(ArchiveType::Os);
+    md.info("name", &image_name)?.info("checksum", &csum)?;
+    if let Some(key) = &sign_key {
+        /*
+         * Record the fingerprint of the key that will sign the archive, so
+         * that it is possible to tell who produced the image even without
+         * the detached signature.
+         */
+        md.info(sign::METADATA_KEY, &sign::fingerprint(&key.verifying_key()))?;
+    }
+    let tar = archive::Archive::new(&tarpath, md.build()?, mtime, compression)?;
 
     for (name, data) in infos {
         tar.add_file_with_data(data, &name)?;
     }
 
-    tar.add_file(&zfsimg, "zfs.img")?;
+    if sparse {
+        tar.add_sparse_file(&zfsimg, "zfs.img")?;
+    } else {
+        tar.add_file(&zfsimg, "zfs.img")?;
+    }
 
     /*
      * Create the boot archive (CPIO) with the kernel and modules that we need
@@ -1929,12 +2534,14 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
     ensure::run(
         log,
         &[
-            "bash",
+            Tool::Bash.path().as_str(),
             mkcpio.to_str().unwrap(),
             &root,
             cpio.to_str().unwrap(),
             tempdir.to_str().unwrap(),
         ],
+        ensure::Timeout::Build,
+        ensure::Retry::NEVER,
     )?;
 
     /*
@@ -1950,7 +2557,7 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
     ensure::run(
         log,
         &[
-            "bash",
+            Tool::Bash.path().as_str(),
             "-c",
             &format!(
                 "'{}' '{}' >'{}'",
@@ -1959,12 +2566,14 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
                 unixz.to_str().unwrap()
             ),
         ],
+        ensure::Timeout::Short,
+        ensure::Retry::NEVER,
     )?;
     tar.add_file(&unixz, "unix.z")?;
     ensure::run(
         log,
         &[
-            "bash",
+            Tool::Bash.path().as_str(),
             "-c",
             &format!(
                 "'{}' '{}' >'{}'",
@@ -1973,6 +2582,8 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
                 cpioz.to_str().unwrap()
             ),
         ],
+        ensure::Timeout::Short,
+        ensure::Retry::NEVER,
     )?;
     tar.add_file(&cpioz, "cpio.z")?;
 
@@ -1986,7 +2597,7 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
         log,
         &phbl_path,
         &[
-            "cargo",
+            Tool::Cargo.path().as_str(),
             "xtask",
             "build",
             "--release",
@@ -1995,6 +2606,8 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
             "--target-dir",
             phbl_target.to_str().unwrap(),
         ],
+        ensure::Timeout::Build,
+        ensure::Retry::NEVER,
     )?;
 
     let reset = rel_path(
@@ -2005,8 +2618,27 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
     let ahib_path = top_path(&["projects", "amd-host-image-builder"])?;
 
     /*
-     * Go through and create the per-board ROM images.
+     * If we are making per-board ROM archives, record which phbl and which
+     * boot archive went into the reset image, as these are common to the ROMs
+     * for all boards.
+     */
+    let rom_info = if rom_archives {
+        let bs = git_branch_status(&phbl_path)?;
+        let phbl_commit =
+            if bs.changes > 0 { format!("{}-dirty", bs.oid) } else { bs.oid };
+
+        Some((phbl_commit, archive::file_sha256(&cpioz)?))
+    } else {
+        None
+    };
+
+    /*
+     * Go through and create the per-board ROM images.  Each ROM is stored at
+     * the top level, where older tools expect to find it, and is also linked
+     * from the roms/ directory so that the ROMs can be found together.  The
+     * directory is always present, even if no ROMs are built.
      */
+    tar.add_directory("roms", archive::Attrs::directory())?;
     for (name, board) in target_boards.iter() {
         if let Some(feat) = &board.feature {
             if !features.contains(feat) {
@@ -2025,8 +2657,9 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
         let efs_path;
         let app_path = board.app_path()?;
         let root_path = top_path(&["image", "amd"])?;
+        let cargo = Tool::Cargo.path();
         let mut args = vec![
-            "cargo",
+            cargo.as_str(),
             "xtask",
             "gen",
             "--amd-firmware",
@@ -2045,9 +2678,56 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
             args.push("--config");
             args.push(efs_path.to_str().unwrap());
         }
-        ensure::run_in(log, &ahib_path, &args)?;
+        ensure::run_in(
+            log,
+            &ahib_path,
+            &args,
+            ensure::Timeout::Build,
+            ensure::Retry::NEVER,
+        )?;
 
         tar.add_file(&rom, &romname)?;
+        tar.add_symlink(&format!("roms/{romname}"), &format!("../{romname}"))?;
+
+        if let Some((phbl_commit, cpio_checksum)) = &rom_info {
+            let rtarpath = rel_path(
+                Some(&outdir),
+                &[&format!("rom-{name}.{}", compression.suffix())],
+            )?;
+            info!(log, "creating ROM archive for {name} at {rtarpath:?}...");
+
+            /*
+             * These archives are not OS images, so they use their own archive
+             * type, rather than one of those known to helios-build-utils.
+             */
+            let mut md: BTreeMap<&str, String> = BTreeMap::new();
+            md.insert("v", "1".into());
+            md.insert("t", "rom".into());
+            md.insert("name", image_name.clone());
+            md.insert("board", name.clone());
+
+            /*
+             * Record the board configuration files relative to the
+             * repository, so that the archive does not depend on where the
+             * repository was checked out.  Files configured with an absolute
+             * path elsewhere are recorded as they were given.
+             */
+            let top = top()?;
+            let rel = |p: &Path| -> String {
+                p.strip_prefix(&top).unwrap_or(p).to_str().unwrap().into()
+            };
+            if let Some(efs) = board.efs_path() {
+                md.insert("efs", rel(&efs?));
+            }
+            md.insert("app", rel(&app_path));
+            md.insert("phbl_commit", phbl_commit.clone());
+            md.insert("cpio_checksum", cpio_checksum.clone());
+
+            let rtar =
+                archive::Archive::new(&rtarpath, md, mtime, compression)?;
+            rtar.add_file(&rom, "rom")?;
+            rtar.finish()?;
+        }
 
         /*
          * Add the Gimlet ROM image again at the original path "rom" for
@@ -2089,7 +2769,7 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
                     log,
                     &ahib_path,
                     &[
-                        "cargo",
+                        Tool::Cargo.path().as_str(),
                         "xtask",
                         "gen",
                         "--amd-firmware",
@@ -2103,8 +2783,14 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
                         "--image",
                         rom.to_str().unwrap(),
                     ],
+                    ensure::Timeout::Build,
+                    ensure::Retry::NEVER,
                 )?;
                 tar.add_file(&rom, &romname)?;
+                tar.add_symlink(
+                    &format!("roms/{romname}"),
+                    &format!("../{romname}"),
+                )?;
             }
         }
     }
@@ -2112,6 +2798,37 @@ fn cmd_image(ca: &CommandArg) -> Result<()> {
     info!(log, "finishing image archive at {tarpath:?}...");
     tar.finish()?;
 
+    /*
+     * The manifest within the archive names files as they appear under
+     * image/, not all of which are left in the output directory.  Store a
+     * separate manifest of the artefacts that are here, so that they can be
+     * checked with "sha256sum -c" without first unpacking the archive.
+     */
+    let mut names = std::fs::read_dir(&outdir)?
+        .map(|ent| Ok(ent?.file_name().to_string_lossy().to_string()))
+        .collect::<Result<Vec<_>>>()?;
+    names.retain(|n| {
+        ["zfs.img", "cpio.z", "unix.z"].contains(&n.as_str())
+            || n.ends_with(".rom")
+    });
+    names.sort();
+    let mut sums = String::new();
+    for n in names {
+        let hash = archive::file_sha256(&rel_path(Some(&outdir), &[&n])?)?;
+        sums += &format!("{hash}  {n}\n");
+    }
+    let sumspath = rel_path(Some(&outdir), &[archive::SHA256SUMS])?;
+    std::fs::write(&sumspath, &sums)?;
+
+    if let Some(key) = &sign_key {
+        let mut sigpath = tarpath.clone().into_os_string();
+        sigpath.push(format!(".{}", sign::SUFFIX));
+        let sigpath = PathBuf::from(sigpath);
+
+        info!(log, "signing image archive into {sigpath:?}...");
+        sign::sign(key, &tarpath, &csum)?.write(&sigpath)?;
+    }
+
     info!(log, "image complete! materials are in {:?}", outdir);
     std::fs::remove_dir_all(&tempdir).ok();
     Ok(())
@@ -2240,13 +2957,16 @@ fn git_branch_point<P: AsRef<Path>>(
     parent_branch: &str,
     commit: &str,
 ) -> Result<String> {
-    let out = Command::new("git")
-        .env_clear()
-        .arg("merge-base")
-        .arg(parent_branch)
-        .arg(commit)
-        .current_dir(path.as_ref())
-        .output()?;
+    let out = ensure::capture(
+        Tool::Git
+            .command()
+            .env_clear()
+            .arg("merge-base")
+            .arg(parent_branch)
+            .arg(commit)
+            .current_dir(path.as_ref()),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!(
@@ -2266,13 +2986,16 @@ fn git_branch_point<P: AsRef<Path>>(
  * will be from the first point to the second point; e.g., "stlouis..HEAD".
  */
 fn git_commit_count<P: AsRef<Path>>(path: P, commit: &str) -> Result<u32> {
-    let out = Command::new("git")
-        .env_clear()
-        .arg("rev-list")
-        .arg("--count")
-        .arg(commit)
-        .current_dir(path.as_ref())
-        .output()?;
+    let out = ensure::capture(
+        Tool::Git
+            .command()
+            .env_clear()
+            .arg("rev-list")
+            .arg("--count")
+            .arg(commit)
+            .current_dir(path.as_ref()),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
         bail!("git commit count ({commit:?}) failed: {}", out.info());
@@ -2282,29 +3005,88 @@ fn git_commit_count<P: AsRef<Path>>(path: P, commit: &str) -> Result<u32> {
     Ok(res.trim().parse()?)
 }
 
-struct BranchStatus {
-    oid: String,
-    head: String,
-}
-
-fn git_branch_status<P: AsRef<Path>>(path: P) -> Result<BranchStatus> {
-    let out = Command::new("git")
-        .env_clear()
-        .arg("status")
-        .arg("--branch")
-        .arg("--porcelain=v2")
-        .current_dir(path.as_ref())
-        .output()?;
+/**
+ * Determine the committer time, in seconds since the epoch, of the nominated
+ * commit.
+ */
+fn git_commit_time<P: AsRef<Path>>(path: P, commit: &str) -> Result<u64> {
+    let out = ensure::capture(
+        Tool::Git
+            .command()
+            .env_clear()
+            .arg("log")
+            .arg("-1")
+            .arg("--format=%ct")
+            .arg(commit)
+            .current_dir(path.as_ref()),
+        ensure::Timeout::Short,
+    )?;
 
     if !out.status.success() {
-        bail!("git branch status failed: {}", out.info());
+        bail!("git commit time ({commit:?}) failed: {}", out.info());
     }
 
     let res = String::from_utf8(out.stdout)?;
+    Ok(res.trim().parse()?)
+}
 
+/**
+ * Check the environment for SOURCE_DATE_EPOCH, as described by the
+ * reproducible builds project, which nominates a fixed timestamp to use in
+ * place of the current time.
+ */
+fn source_date_epoch() -> Result<Option<u64>> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(v) => Ok(Some(v.trim().parse().map_err(|e| {
+            anyhow!("invalid SOURCE_DATE_EPOCH value {v:?}: {e}")
+        })?)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => bail!("SOURCE_DATE_EPOCH: {e}"),
+    }
+}
+
+struct BranchStatus {
+    oid: String,
+    head: String,
+    /*
+     * The number of changed or untracked files in the working tree:
+     */
+    changes: usize,
+}
+
+fn git_branch_status<P: AsRef<Path>>(path: P) -> Result<BranchStatus> {
+    let out = ensure::capture(
+        Tool::Git
+            .command()
+            .env_clear()
+            .arg("status")
+            .arg("--branch")
+            .arg("--porcelain=v2")
+            .current_dir(path.as_ref()),
+        ensure::Timeout::Short,
+    )?;
+
+    if !out.status.success() {
+        bail!("git branch status failed: {}", out.info());
+    }
+
+    parse_branch_status(&String::from_utf8(out.stdout)?)
+}
+
+/**
+ * Parse the output of "git status --branch --porcelain=v2", either from a live
+ * repository or as stored in an image archive.
+ */
+fn parse_branch_status(res: &str) -> Result<BranchStatus> {
     let mut oid = None;
     let mut head = None;
+    let mut changes = 0;
     for l in res.lines() {
+        if !l.is_empty() && !l.starts_with('#') {
+            changes += 1;
+            continue;
+        }
+
         let t = l.split_ascii_whitespace().collect::<Vec<_>>();
         if t.len() < 3 || t[0] != "#" {
             continue;
@@ -2330,14 +3112,31 @@ fn git_branch_status<P: AsRef<Path>>(path: P) -> Result<BranchStatus> {
     }
 
     if let Some((oid, head)) = oid.zip(head) {
-        Ok(BranchStatus { oid, head })
+        Ok(BranchStatus { oid, head, changes })
     } else {
         bail!("oid or head missing from branch status? {res:?}");
     }
 }
 
+/*
+ * git(1) only reports progress when its standard error is a terminal, but
+ * ensure::status() passes that through a pipe.  If the output is going to end
+ * up on a terminal anyway, ask for progress explicitly.
+ */
+fn git_progress(cmd: &mut Command) -> &mut Command {
+    if std::io::stderr().is_terminal() {
+        cmd.arg("--progress");
+    }
+    cmd
+}
+
 fn cmd_setup(ca: &CommandArg) -> Result<()> {
-    let opts = baseopts();
+    let mut opts = baseopts();
+    opts.optflag(
+        "n",
+        "dry-run",
+        "report the changes that setup would make, without making them",
+    );
 
     let usage = || {
         println!("{}", opts.usage("Usage: helios [OPTIONS] setup [OPTIONS]"));
@@ -2351,10 +3150,16 @@ fn cmd_setup(ca: &CommandArg) -> Result<()> {
         return Ok(());
     }
 
+    let dry_run = res.opt_present("dry-run");
+    ensure::set_dry_run(dry_run);
+
     let relver = determine_release_version()?;
 
     let top = top()?;
     info!(log, "helios repository root is: {}", top.display());
+    if dry_run {
+        info!(log, "dry run: no changes will be made");
+    }
 
     /*
      * Read the projects file which contains the URLs of the repositories we
@@ -2362,13 +3167,15 @@ fn cmd_setup(ca: &CommandArg) -> Result<()> {
      */
     let p: Projects = read_toml(top_path(&["config", "projects.toml"])?)?;
 
-    ensure_dir(&["projects"])?;
-    ensure_dir(&["tmp"])?;
+    ensure_dir(log, &["projects"])?;
+    ensure_dir(log, &["tmp"])?;
+    let hash_cache = top_path(&["tmp", "ensure-hashes.json"])?;
+    ensure::set_hash_cache(Some(&hash_cache))?;
 
     for (name, project) in p.project.iter() {
         let path = top_path(&["projects", name])?;
         let url = project.url(false)?;
-        let tmp = ensure_dir(&["tmp", name])?;
+        let tmp = ensure_dir(log, &["tmp", name])?;
 
         if let Some(reason) = project.skip_reason() {
             info!(log, "skipping project {name:?} because {reason}");
@@ -2380,23 +3187,18 @@ fn cmd_setup(ca: &CommandArg) -> Result<()> {
 
         if exists_dir(&path)? {
             info!(log, "clone {url} exists already at {path:?}");
-            if project.auto_update {
+            if project.auto_update && dry_run {
+                info!(log, "dry run: would fetch updates for clone");
+            } else if project.auto_update {
                 info!(log, "fetching updates for clone ...");
-                let mut child = if let Some(rev) = &project.rev {
-                    Command::new("git")
-                        .current_dir(&path)
-                        .arg("fetch")
-                        .arg("origin")
-                        .arg(rev)
-                        .spawn()?
-                } else {
-                    Command::new("git")
-                        .current_dir(&path)
-                        .arg("fetch")
-                        .spawn()?
-                };
+                let mut cmd = Tool::Git.command();
+                git_progress(cmd.current_dir(&path).arg("fetch"));
+                if let Some(rev) = &project.rev {
+                    cmd.arg("origin").arg(rev);
+                }
 
-                let exit = child.wait()?;
+                let exit =
+                    ensure::status(&log, &mut cmd, ensure::Retry::NETWORK)?;
                 if !exit.success() {
                     bail!("fetch in {} failed", path.display());
                 }
@@ -2414,13 +3216,15 @@ fn cmd_setup(ca: &CommandArg) -> Result<()> {
                             "applying fixup: moving to branch {}...",
                             fixup.to_branch
                         );
-                        let mut child = Command::new("git")
-                            .current_dir(&path)
-                            .arg("checkout")
-                            .arg(&fixup.to_branch)
-                            .spawn()?;
-
-                        let exit = child.wait()?;
+                        let exit = ensure::status(
+                            &log,
+                            Tool::Git
+                                .command()
+                                .current_dir(&path)
+                                .arg("checkout")
+                                .arg(&fixup.to_branch),
+                            ensure::Retry::NEVER,
+                        )?;
                         if !exit.success() {
                             bail!("branch switch in {} failed", path.display());
                         }
@@ -2429,92 +3233,118 @@ fn cmd_setup(ca: &CommandArg) -> Result<()> {
 
                 if let Some(rev) = &project.rev {
                     info!(log, "pinning to revision {rev}...");
-                    let mut child = Command::new("git")
-                        .current_dir(&path)
-                        .arg("checkout")
-                        .arg(rev)
-                        .spawn()?;
-
-                    let exit = child.wait()?;
+                    let exit = ensure::status(
+                        &log,
+                        Tool::Git
+                            .command()
+                            .current_dir(&path)
+                            .arg("checkout")
+                            .arg(rev),
+                        ensure::Retry::NEVER,
+                    )?;
                     if !exit.success() {
                         bail!("update merge in {} failed", path.display());
                     }
                 } else {
                     info!(log, "rolling branch forward...");
-                    let mut child = Command::new("git")
-                        .current_dir(&path)
-                        .arg("merge")
-                        .arg("--ff-only")
-                        .spawn()?;
-
-                    let exit = child.wait()?;
+                    let exit = ensure::status(
+                        &log,
+                        Tool::Git
+                            .command()
+                            .current_dir(&path)
+                            .arg("merge")
+                            .arg("--ff-only"),
+                        ensure::Retry::NEVER,
+                    )?;
                     if !exit.success() {
                         bail!("update merge in {} failed", path.display());
                     }
                 }
 
                 info!(log, "updating submodules...");
-                let mut child = Command::new("git")
-                    .current_dir(&path)
-                    .arg("submodule")
-                    .arg("update")
-                    .arg("--recursive")
-                    .spawn()?;
-
-                let exit = child.wait()?;
+                let exit = ensure::status(
+                    &log,
+                    git_progress(
+                        Tool::Git
+                            .command()
+                            .current_dir(&path)
+                            .arg("submodule")
+                            .arg("update"),
+                    )
+                    .arg("--recursive"),
+                    ensure::Retry::NETWORK,
+                )?;
                 if !exit.success() {
                     bail!("submodule update in {} failed", path.display());
                 }
             }
+        } else if dry_run {
+            /*
+             * Without the clone, there is nothing more we can check for this
+             * project.
+             */
+            info!(log, "dry run: would clone {url} at {path:?}");
+            continue;
         } else {
             info!(log, "cloning {url} at {path:?}...");
-            let mut child = Command::new("git")
-                .arg("clone")
-                .arg("--recurse-submodules")
-                .arg(&url)
-                .arg(&path)
-                .spawn()?;
-
-            let exit = child.wait()?;
+            /*
+             * A failed clone removes the directory it was creating, so it is
+             * safe to try again.
+             */
+            let exit = ensure::status(
+                &log,
+                git_progress(Tool::Git.command().arg("clone"))
+                    .arg("--recurse-submodules")
+                    .arg(&url)
+                    .arg(&path),
+                ensure::Retry::NETWORK,
+            )?;
             if !exit.success() {
                 bail!("clone of {} to {} failed", url, path.display());
             }
 
             if let Some(rev) = &project.rev {
                 info!(log, "fetching revision {rev} for clone ...");
-                let mut child = Command::new("git")
-                    .current_dir(&path)
-                    .arg("fetch")
+                let exit = ensure::status(
+                    &log,
+                    git_progress(
+                        Tool::Git.command().current_dir(&path).arg("fetch"),
+                    )
                     .arg("origin")
-                    .arg(rev)
-                    .spawn()?;
-
-                let exit = child.wait()?;
+                    .arg(rev),
+                    ensure::Retry::NETWORK,
+                )?;
                 if !exit.success() {
                     bail!("fetch in {} failed", path.display());
                 }
 
                 info!(log, "pinning to revision {rev}...");
-                let mut child = Command::new("git")
-                    .current_dir(&path)
-                    .arg("checkout")
-                    .arg(rev)
-                    .spawn()?;
-
-                let exit = child.wait()?;
+                let exit = ensure::status(
+                    &log,
+                    Tool::Git
+                        .command()
+                        .current_dir(&path)
+                        .arg("checkout")
+                        .arg(rev),
+                    ensure::Retry::NEVER,
+                )?;
                 if !exit.success() {
                     bail!("update merge in {} failed", path.display());
                 }
 
                 info!(log, "updating submodules...");
-                let mut child = Command::new("git")
-                    .current_dir(&path)
-                    .arg("submodule")
-                    .arg("update")
-                    .arg("--recursive")
-                    .spawn()?;
-
-                let exit = child.wait()?;
+                let exit = ensure::status(
+                    &log,
+                    git_progress(
+                        Tool::Git
+                            .command()
+                            .current_dir(&path)
+                            .arg("submodule")
+                            .arg("update"),
+                    )
+                    .arg("--recursive"),
+                    ensure::Retry::NETWORK,
+                )?;
                 if !exit.success() {
                     bail!("submodule update in {} failed", path.display());
                 }
@@ -2546,6 +3376,8 @@ fn cmd_setup(ca: &CommandArg) -> Result<()> {
                 &site_sh,
                 &ssp,
                 0o644,
+                None,
+                None,
                 ensure::Create::Always,
             )?;
         }
@@ -2571,7 +3403,7 @@ fn cmd_setup(ca: &CommandArg) -> Result<()> {
      * packages after build and transformations are applied.
      */
     let publisher = relver.publisher_name();
-    ensure_dir(&["packages"])?;
+    ensure_dir(log, &["packages"])?;
     for repo in &["os", "other", "combined"] {
         let repo_path = top_path(&["packages", repo])?;
         create_ips_repo(log, &repo_path, &publisher, false)?;
@@ -2596,6 +3428,12 @@ fn cmd_setup(ca: &CommandArg) -> Result<()> {
         }
 
         let path = top_path(&["projects", name])?;
+        if dry_run {
+            if project.cargo_toolchain || project.cargo_build {
+                info!(log, "dry run: would set up project {name:?}");
+            }
+            continue;
+        }
         if project.cargo_toolchain || project.cargo_build {
             rustup_install_toolchain(log, &path)?;
         }
@@ -2605,182 +3443,942 @@ fn cmd_setup(ca: &CommandArg) -> Result<()> {
 
         info!(log, "building project {:?} at {}", name, path.display());
         let start = Instant::now();
-        let mut args = vec!["cargo", "build", "--locked"];
+        let cargo = Tool::Cargo.path();
+        let mut args = vec![cargo.as_str(), "build", "--locked"];
         if !project.use_debug {
             args.push("--release");
         }
-        ensure::run_in(log, &path, &args)?;
+        /*
+         * Cargo may need to download crates, and a failed build can be
+         * repeated without harm.
+         */
+        ensure::run_in(
+            log,
+            &path,
+            &args,
+            ensure::Timeout::Build,
+            ensure::Retry::NETWORK,
+        )?;
         let delta = Instant::now().saturating_duration_since(start).as_secs();
         info!(log, "building project {:?} ok ({} seconds)", name, delta);
     }
 
+    ensure::set_hash_cache(None)?;
+
     Ok(())
 }
 
-struct CommandArg<'a> {
-    log: &'a Logger,
-    args: &'a [&'a str],
+struct PkgFmri {
+    publisher: String,
+    name: String,
+    version: String,
 }
 
-struct CommandInfo {
-    name: &'static str,
-    desc: &'static str,
-    func: fn(&CommandArg) -> Result<()>,
-    hide: bool,
-    blank: bool,
-}
+/**
+ * Parse the output of "pkg list -H -v", as stored in an image archive, into a
+ * list of package FMRIs.
+ */
+fn parse_pkg_list(list: &str) -> Result<Vec<PkgFmri>> {
+    let mut out = Vec::new();
 
-fn main() -> Result<()> {
-    let mut opts = baseopts();
-    opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
+    for l in list.lines() {
+        let Some(fmri) = l.split_ascii_whitespace().next() else {
+            continue;
+        };
 
-    let handlers = [
-        CommandInfo {
-            name: "setup",
-            desc: "clone required repositories and run setup tasks",
-            func: cmd_setup,
-            hide: false,
-            blank: false,
-        },
-        CommandInfo {
-            name: "genenv",
-            desc: "generate environment file for illumos build",
-            func: cmd_illumos_genenv,
-            hide: false,
-            blank: true,
-        },
-        CommandInfo {
-            name: "bldenv",
-            desc: "enter a bldenv shell for illumos so you can run dmake",
-            func: cmd_illumos_bldenv,
-            hide: false,
-            blank: true,
-        },
-        CommandInfo {
-            name: "onu",
-            desc: "install your non-DEBUG build of illumos on this system",
-            func: cmd_illumos_onu,
-            hide: false,
-            blank: false,
-        },
-        CommandInfo {
-            name: "build-illumos",
-            desc: "run a full nightly(1) and produce packages",
-            func: cmd_build_illumos,
-            hide: false,
-            blank: false,
-        },
-        CommandInfo {
-            name: "merge-illumos",
-            desc: "merge DEBUG and non-DEBUG packages into one repository",
-            func: cmd_merge_illumos,
-            hide: false,
-            blank: false,
-        },
-        CommandInfo {
-            name: "experiment-image",
-            desc: "image construction for Compute Sleds",
-            func: cmd_image,
-            hide: false,
-            blank: false,
-        },
-        CommandInfo {
-            name: "image",
-            desc: "image construction for Compute Sleds",
-            func: cmd_image,
-            hide: true,
-            blank: false,
-        },
-        CommandInfo {
-            name: "help",
-            desc: "display usage information",
-            /*
-             * No behaviour is required here.  The "help" command is a special case
-             * in the argument processing below.
-             */
-            func: |_: &CommandArg| Ok(()),
-            hide: false,
-            blank: true,
-        },
-    ];
+        let Some((publisher, rest)) =
+            fmri.strip_prefix("pkg://").and_then(|s| s.split_once('/'))
+        else {
+            bail!("unexpected package FMRI {fmri:?}");
+        };
+        let Some((name, version)) = rest.split_once('@') else {
+            bail!("package FMRI {fmri:?} has no version");
+        };
 
-    let usage = |failure: bool| {
-        let mut out = String::new();
-        out += "Usage: helios [OPTIONS] COMMAND [OPTIONS] [ARGS...]\n\n";
-        for ci in handlers.iter() {
-            if ci.hide {
-                continue;
-            }
+        out.push(PkgFmri {
+            publisher: publisher.to_string(),
+            name: name.to_string(),
+            version: version.to_string(),
+        });
+    }
 
-            if ci.blank {
-                out += "\n";
-            }
+    Ok(out)
+}
 
-            out += &format!("    {:<16} {}\n", ci.name, ci.desc);
+/**
+ * Parse the output of "pkg publisher -F tsv", as stored in an image archive,
+ * into a list of publisher names and origin URIs in search order.
+ */
+fn parse_pkg_publishers(list: &str) -> Result<Vec<(String, String)>> {
+    let mut out = Vec::new();
+
+    for l in list.lines() {
+        let t = l.split('\t').collect::<Vec<_>>();
+        if t.len() < 7 {
+            if l.trim().is_empty() {
+                continue;
+            }
+            bail!("unexpected publisher line {l:?}");
         }
-        let msg = opts.usage(&out);
-        if failure {
-            eprintln!("{msg}");
-        } else {
-            println!("{msg}");
+        if t[0] == "PUBLISHER" {
+            continue;
         }
-    };
 
-    let res = match opts.parse(std::env::args_os().skip(1)) {
-        Ok(res) => res,
-        Err(e) => {
-            usage(true);
-            bail!("{e}");
-        }
+        out.push((t[0].to_string(), t[6].to_string()));
+    }
+
+    Ok(out)
+}
+
+/**
+ * The image arguments file contains the debug representation of the argument
+ * list passed to the image command.  Try to recover the original arguments.
+ */
+fn parse_image_args(s: &str) -> Option<Vec<String>> {
+    let list = s.trim().strip_prefix("image arguments:")?.trim();
+    let list = list.strip_prefix('[')?.strip_suffix(']')?;
+
+    list.lines()
+        .map(|l| l.trim().trim_end_matches(','))
+        .filter(|l| !l.is_empty())
+        .map(|l| serde_json::from_str::<String>(l).ok())
+        .collect()
+}
+
+fn cmd_inspect_archive(ca: &CommandArg) -> Result<()> {
+    let opts = baseopts();
+
+    let usage = || {
+        println!(
+            "{}",
+            opts.usage("Usage: helios [OPTIONS] inspect-archive ARCHIVE")
+        );
     };
 
+    let log = ca.log;
+    let res = opts.parse(ca.args)?;
+
     if res.opt_present("help") {
-        usage(false);
+        usage();
         return Ok(());
     }
 
-    if res.free.is_empty() {
-        usage(true);
-        bail!("choose a command");
+    if res.free.len() != 1 {
+        usage();
+        bail!("specify the path to one image archive");
     }
 
-    if res.free[0] == "help" {
-        usage(false);
-        return Ok(());
+    let path = PathBuf::from(&res.free[0]);
+    info!(log, "reading archive {path:?}...");
+    let c = archive::Contents::read(&path)?;
+
+    println!("metadata:");
+    for (k, v) in c.metadata.iter() {
+        println!("    {k:<16} {v}");
     }
 
-    let args = res.free[1..].iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    println!();
+    println!("files:");
+    for e in c.entries.iter() {
+        println!("    {:04o} {:>14}  {}  {}", e.mode, e.size, e.sha256, e.name);
+    }
+    for (name, target) in c.links.iter() {
+        println!("    {name} -> {target}");
+    }
 
-    let log = init_log();
+    if let Some(args) = c.infos.get("image-args.txt") {
+        println!();
+        println!("image arguments:");
+        if let Some(args) = parse_image_args(args) {
+            println!("    image {}", args.join(" "));
+        } else {
+            for l in args.lines() {
+                println!("    {l}");
+            }
+        }
+    }
 
-    for ci in handlers.iter() {
-        if ci.name != res.free[0] {
+    for (name, status) in c.infos.iter() {
+        let Some(project) = name
+            .strip_prefix("git-status-")
+            .and_then(|n| n.strip_suffix(".txt"))
+        else {
             continue;
+        };
+
+        let bs = parse_branch_status(status)?;
+        println!();
+        println!("project {project}:");
+        println!("    commit           {}", bs.oid);
+        println!("    branch           {}", bs.head);
+        if bs.changes > 0 {
+            println!("    local changes    {} files", bs.changes);
         }
+    }
 
-        let ca = CommandArg { log: &log, args: args.as_slice() };
+    if let Some(list) = c.infos.get("pkg-list.txt") {
+        let pkgs = parse_pkg_list(list)?;
+        let width = pkgs.iter().map(|p| p.name.len()).max().unwrap_or(0);
 
-        return (ci.func)(&ca);
+        println!();
+        println!("packages ({}):", pkgs.len());
+        for p in pkgs {
+            println!(
+                "    {:<width$}  {}  ({})",
+                p.name, p.version, p.publisher
+            );
+        }
     }
 
-    usage(true);
-    bail!("command \"{}\" not understood", res.free[0]);
-}
-
-/*
- * Extract a hash from a string produced by:
- *    git describe --all --long --dirty
- */
-fn extract_hash(s: &str) -> Option<&str> {
     /*
-     * Look from the end for a column that appears to be the git hash.
+     * Check that the boot image matches the checksum recorded in the archive
+     * metadata, and that the data in the image matches its own header.
      */
-    s.split('-').rev().find_map(|col| {
-        /*
-         * Require column that starts with `g`; strip it off.
-         */
-        let suffix = col.strip_prefix('g')?;
-
+    let mut problems = Vec::new();
+    println!();
+    if let Some(bi) = &c.boot_image {
+        println!("boot image (zfs.img):");
+        println!("    name             {}", bi.image_name);
+        println!("    dataset          {}", bi.dataset_name);
+        println!("    header version   {}", bi.version);
+        println!("    compressed       {}", bi.compressed);
+        println!("    data size        {}", bi.data_size);
+        println!("    image size       {}", bi.image_size);
+        println!("    target size      {}", bi.target_size);
+        println!("    checksum         {}", bi.checksum);
+
+        match c.metadata.get("checksum") {
+            Some(csum) if *csum == bi.checksum => {
+                println!("    metadata         checksum matches");
+            }
+            Some(csum) => {
+                println!("    metadata         MISMATCH ({csum})");
+                problems.push("boot image checksum does not match metadata");
+            }
+            None => {
+                println!("    metadata         no checksum recorded");
+            }
+        }
+
+        match bi.data_ok() {
+            Some(true) => println!("    data             checksum matches"),
+            Some(false) => {
+                println!(
+                    "    data             MISMATCH ({})",
+                    bi.computed.as_deref().unwrap()
+                );
+                problems.push("boot image data does not match its checksum");
+            }
+            None => println!("    data             not checked (compressed)"),
+        }
+    } else if c.entries.iter().any(|e| e.name == "zfs.img") {
+        println!("boot image (zfs.img): header not valid");
+    } else {
+        println!("boot image (zfs.img): not present");
+    }
+    problems.extend(c.problems.iter().map(String::as_str));
+
+    /*
+     * Check each file against the manifest, if the archive has one:
+     */
+    println!();
+    if let Some(sums) = c.infos.get(archive::SHA256SUMS) {
+        let sums = archive::parse_sums(sums)?;
+        let mut bad = 0;
+        for e in c.entries.iter().filter(|e| e.name != archive::SHA256SUMS) {
+            if sums.get(&e.name) != Some(&e.sha256) {
+                println!("    {}: MISMATCH", e.name);
+                bad += 1;
+            }
+        }
+        for name in sums.keys() {
+            if !c.entries.iter().any(|e| &e.name == name) {
+                println!("    {name}: MISSING");
+                bad += 1;
+            }
+        }
+        if bad == 0 {
+            println!("manifest: all {} files match", sums.len());
+        } else {
+            problems.push("files do not match the manifest");
+        }
+    } else {
+        println!("manifest: not present");
+    }
+
+    if !problems.is_empty() {
+        bail!("archive {path:?}: {}", problems.join("; "));
+    }
+
+    Ok(())
+}
+
+fn cmd_extract_archive(ca: &CommandArg) -> Result<()> {
+    let opts = baseopts();
+
+    let usage = || {
+        println!(
+            "{}",
+            opts.usage("Usage: helios [OPTIONS] extract-archive ARCHIVE DIR")
+        );
+    };
+
+    let log = ca.log;
+    let res = opts.parse(ca.args)?;
+
+    if res.opt_present("help") {
+        usage();
+        return Ok(());
+    }
+
+    if res.free.len() != 2 {
+        usage();
+        bail!("specify the path to an image archive and a directory");
+    }
+
+    let path = PathBuf::from(&res.free[0]);
+    let dir = PathBuf::from(&res.free[1]);
+    if dir.exists() && std::fs::read_dir(&dir)?.next().is_some() {
+        bail!("{dir:?} exists and is not empty");
+    }
+    std::fs::create_dir_all(&dir)?;
+
+    info!(log, "extracting archive {path:?} into {dir:?}...");
+    archive::extract(&path, &dir)?;
+
+    info!(log, "extraction complete");
+    Ok(())
+}
+
+/**
+ * Compare two maps, producing a line for each key that was added, removed, or
+ * changed.
+ */
+fn diff_maps(
+    a: &BTreeMap<String, String>,
+    b: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let width = a.keys().chain(b.keys()).map(|k| k.len()).max().unwrap_or(0);
+
+    let mut out = Vec::new();
+    for (k, va) in a.iter() {
+        match b.get(k) {
+            None => out.push(format!("- {k:<width$}  {va}")),
+            Some(vb) if va != vb => {
+                out.push(format!("~ {k:<width$}  {va} -> {vb}"))
+            }
+            Some(_) => (),
+        }
+    }
+    for (k, vb) in b.iter() {
+        if !a.contains_key(k) {
+            out.push(format!("+ {k:<width$}  {vb}"));
+        }
+    }
+    out
+}
+
+struct ArchiveSummary {
+    metadata: BTreeMap<String, String>,
+    packages: BTreeMap<String, String>,
+    publishers: BTreeMap<String, String>,
+    search_order: Vec<String>,
+    projects: BTreeMap<String, String>,
+    files: BTreeMap<String, String>,
+}
+
+impl ArchiveSummary {
+    fn from_contents(c: &archive::Contents) -> Result<ArchiveSummary> {
+        let mut packages = BTreeMap::new();
+        if let Some(list) = c.infos.get("pkg-list.txt") {
+            for p in parse_pkg_list(list)? {
+                packages
+                    .insert(p.name, format!("{} ({})", p.version, p.publisher));
+            }
+        }
+
+        let mut publishers: BTreeMap<String, String> = BTreeMap::new();
+        let mut search_order = Vec::new();
+        if let Some(list) = c.infos.get("pkg-publishers.txt") {
+            for (name, uri) in parse_pkg_publishers(list)? {
+                if !search_order.contains(&name) {
+                    search_order.push(name.clone());
+                }
+                let origins = publishers.entry(name).or_default();
+                if !origins.is_empty() {
+                    *origins += ", ";
+                }
+                *origins += &uri;
+            }
+        }
+
+        let mut projects = BTreeMap::new();
+        for (name, status) in c.infos.iter() {
+            let Some(project) = name
+                .strip_prefix("git-status-")
+                .and_then(|n| n.strip_suffix(".txt"))
+            else {
+                continue;
+            };
+
+            let bs = parse_branch_status(status)?;
+            let mut v = format!("{} ({})", bs.oid, bs.head);
+            if bs.changes > 0 {
+                v += &format!(" +{} local changes", bs.changes);
+            }
+            projects.insert(project.to_string(), v);
+        }
+
+        let mut files = c
+            .entries
+            .iter()
+            .map(|e| (e.name.clone(), format!("{} {}", e.size, e.sha256)))
+            .collect::<BTreeMap<_, _>>();
+        for (name, target) in c.links.iter() {
+            files.insert(name.clone(), format!("-> {target}"));
+        }
+
+        Ok(ArchiveSummary {
+            metadata: c.metadata.clone(),
+            packages,
+            publishers,
+            search_order,
+            projects,
+            files,
+        })
+    }
+}
+
+/**
+ * Compare two archives, section by section, in the form produced by
+ * diff_maps().
+ */
+fn diff_summaries(
+    a: &ArchiveSummary,
+    b: &ArchiveSummary,
+) -> Vec<(&'static str, Vec<String>)> {
+    let mut publishers = diff_maps(&a.publishers, &b.publishers);
+    if a.search_order != b.search_order {
+        publishers.push(format!(
+            "~ search order  {} -> {}",
+            a.search_order.join(", "),
+            b.search_order.join(", "),
+        ));
+    }
+
+    vec![
+        ("metadata", diff_maps(&a.metadata, &b.metadata)),
+        ("publishers", publishers),
+        ("projects", diff_maps(&a.projects, &b.projects)),
+        ("packages", diff_maps(&a.packages, &b.packages)),
+        ("files", diff_maps(&a.files, &b.files)),
+    ]
+}
+
+fn cmd_diff_archive(ca: &CommandArg) -> Result<()> {
+    let opts = baseopts();
+
+    let usage = || {
+        println!(
+            "{}",
+            opts.usage("Usage: helios [OPTIONS] diff-archive ARCHIVE ARCHIVE")
+        );
+    };
+
+    let log = ca.log;
+    let res = opts.parse(ca.args)?;
+
+    if res.opt_present("help") {
+        usage();
+        return Ok(());
+    }
+
+    if res.free.len() != 2 {
+        usage();
+        bail!("specify the paths to two image archives");
+    }
+
+    let mut sums = Vec::new();
+    for p in res.free.iter() {
+        let path = PathBuf::from(p);
+        info!(log, "reading archive {path:?}...");
+        let c = archive::Contents::read(&path)?;
+        for p in c.problems.iter() {
+            slog::warn!(log, "archive {path:?}: {p}");
+        }
+        sums.push(ArchiveSummary::from_contents(&c)?);
+    }
+
+    println!("--- {}", res.free[0]);
+    println!("+++ {}", res.free[1]);
+
+    let sections = diff_summaries(&sums[0], &sums[1]);
+
+    let mut total = 0;
+    for (title, lines) in sections.iter() {
+        if lines.is_empty() {
+            continue;
+        }
+
+        let count = |c: char| lines.iter().filter(|l| l.starts_with(c)).count();
+        println!();
+        println!(
+            "{title}: {} added, {} removed, {} changed",
+            count('+'),
+            count('-'),
+            count('~'),
+        );
+        for l in lines.iter() {
+            println!("    {l}");
+        }
+        total += lines.len();
+    }
+
+    if total == 0 {
+        println!();
+        println!("no differences");
+    }
+
+    Ok(())
+}
+
+fn cmd_verify_archive(ca: &CommandArg) -> Result<()> {
+    let mut opts = baseopts();
+    opts.optopt(
+        "k",
+        "",
+        "verify the signature with the trusted Ed25519 public key in this \
+        file (required)",
+        "KEYFILE",
+    );
+    opts.optopt(
+        "s",
+        "",
+        "read the signature from this file (default: ARCHIVE.sig)",
+        "SIGFILE",
+    );
+
+    let usage = || {
+        println!(
+            "{}",
+            opts.usage(
+                "Usage: helios [OPTIONS] verify-archive [OPTIONS] -k KEYFILE \
+                ARCHIVE"
+            )
+        );
+    };
+
+    let log = ca.log;
+    let res = opts.parse(ca.args)?;
+
+    if res.opt_present("help") {
+        usage();
+        return Ok(());
+    }
+
+    if res.free.len() != 1 {
+        usage();
+        bail!("specify the path to one image archive");
+    }
+
+    /*
+     * The signature file includes the public key of whoever signed it, but
+     * that says nothing about whether the archive is authentic: anybody who
+     * could alter the archive could also sign it again with their own key.
+     * We must be told which key to trust.
+     */
+    let Some(keyfile) = res.opt_str("k") else {
+        usage();
+        bail!("specify the trusted public key with -k");
+    };
+    let key = sign::load_verifying_key(&PathBuf::from(&keyfile))?;
+    let fp = sign::fingerprint(&key);
+
+    let path = PathBuf::from(&res.free[0]);
+    let sigpath = if let Some(p) = res.opt_str("s") {
+        PathBuf::from(p)
+    } else {
+        let mut p = path.clone().into_os_string();
+        p.push(format!(".{}", sign::SUFFIX));
+        PathBuf::from(p)
+    };
+
+    info!(log, "reading signature {sigpath:?}...");
+    let sig = sign::Sidecar::read(&sigpath)?;
+    let sigkey = sig.verifying_key()?;
+    let sigfp = sign::fingerprint(&sigkey);
+
+    let mut problems = Vec::new();
+
+    println!("signature:");
+    println!("    trusted key      {fp}");
+    if sigkey == key {
+        println!("    key              matches");
+    } else {
+        println!("    key              MISMATCH ({sigfp})");
+        problems.push("archive was not signed by the trusted key");
+    }
+    if sig.fingerprint != sigfp {
+        println!("    fingerprint      MISMATCH ({})", sig.fingerprint);
+        problems.push("key fingerprint does not match the public key");
+    }
+
+    /*
+     * Check the signature over the archive as a whole:
+     */
+    info!(log, "hashing archive {path:?}...");
+    let sha256 = archive::file_sha256(&path)?;
+    println!();
+    println!("archive:");
+    println!("    sha256           {sha256}");
+    if sig.archive.sha256 != sha256 {
+        println!("    signed sha256    MISMATCH ({})", sig.archive.sha256);
+        problems.push("archive does not match the signed hash");
+    }
+    match sig.verify_archive(&key) {
+        Ok(()) => println!("    signature        ok"),
+        Err(e) => {
+            println!("    signature        BAD ({e})");
+            problems.push("archive signature is not valid");
+        }
+    }
+
+    /*
+     * Check that the signed boot image checksum agrees with both the archive
+     * metadata and the image itself, and that the metadata names the key that
+     * was used to sign the archive.
+     */
+    info!(log, "reading archive {path:?}...");
+    let c = archive::Contents::read(&path)?;
+    println!();
+    println!("boot image (zfs.img):");
+    println!("    sha256           {}", sig.zfs_img.sha256);
+    match sig.verify_zfs_img(&key) {
+        Ok(()) => println!("    signature        ok"),
+        Err(e) => {
+            println!("    signature        BAD ({e})");
+            problems.push("boot image signature is not valid");
+        }
+    }
+    if c.metadata.get("checksum") != Some(&sig.zfs_img.sha256) {
+        println!("    metadata         MISMATCH");
+        problems.push("signed boot image checksum does not match metadata");
+    }
+    if let Some(bi) = &c.boot_image {
+        if bi.checksum != sig.zfs_img.sha256 || bi.data_ok() == Some(false) {
+            println!("    image            MISMATCH");
+            problems.push("boot image does not match the signed checksum");
+        }
+    } else if c.entries.iter().any(|e| e.name == "zfs.img") {
+        println!("    image            header not valid");
+    } else {
+        println!("    image            not present");
+        problems.push("archive does not contain a boot image");
+    }
+    problems.extend(c.problems.iter().map(String::as_str));
+
+    println!();
+    match c.metadata.get(sign::METADATA_KEY) {
+        Some(v) if *v == fp => println!("metadata: signing key matches"),
+        Some(v) => {
+            println!("metadata: signing key MISMATCH ({v})");
+            problems.push("metadata names a different signing key");
+        }
+        None => {
+            println!("metadata: no signing key recorded");
+            problems.push("metadata does not name a signing key");
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!("archive {path:?}: {}", problems.join("; "));
+    }
+
+    Ok(())
+}
+
+struct CommandArg<'a> {
+    log: &'a Logger,
+    args: &'a [&'a str],
+}
+
+struct CommandInfo {
+    name: &'static str,
+    desc: &'static str,
+    func: fn(&CommandArg) -> Result<()>,
+    hide: bool,
+    blank: bool,
+}
+
+fn main() -> Result<()> {
+    let mut opts = baseopts();
+    opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
+    opts.optopt(
+        "",
+        "log-format",
+        "format for the log on stdout: \"term\" (default) or \"json\"",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "log-file",
+        "also append the log, as JSON, to a file",
+        "PATH",
+    );
+    opts.optflagmulti("v", "verbose", "log more detail (may be repeated)");
+    opts.optflagmulti("q", "quiet", "log less detail (may be repeated)");
+
+    let handlers = [
+        CommandInfo {
+            name: "setup",
+            desc: "clone required repositories and run setup tasks",
+            func: cmd_setup,
+            hide: false,
+            blank: false,
+        },
+        CommandInfo {
+            name: "genenv",
+            desc: "generate environment file for illumos build",
+            func: cmd_illumos_genenv,
+            hide: false,
+            blank: true,
+        },
+        CommandInfo {
+            name: "bldenv",
+            desc: "enter a bldenv shell for illumos so you can run dmake",
+            func: cmd_illumos_bldenv,
+            hide: false,
+            blank: true,
+        },
+        CommandInfo {
+            name: "onu",
+            desc: "install your non-DEBUG build of illumos on this system",
+            func: cmd_illumos_onu,
+            hide: false,
+            blank: false,
+        },
+        CommandInfo {
+            name: "build-illumos",
+            desc: "run a full nightly(1) and produce packages",
+            func: cmd_build_illumos,
+            hide: false,
+            blank: false,
+        },
+        CommandInfo {
+            name: "merge-illumos",
+            desc: "merge DEBUG and non-DEBUG packages into one repository",
+            func: cmd_merge_illumos,
+            hide: false,
+            blank: false,
+        },
+        CommandInfo {
+            name: "experiment-image",
+            desc: "image construction for Compute Sleds",
+            func: cmd_image,
+            hide: false,
+            blank: false,
+        },
+        CommandInfo {
+            name: "image",
+            desc: "image construction for Compute Sleds",
+            func: cmd_image,
+            hide: true,
+            blank: false,
+        },
+        CommandInfo {
+            name: "inspect-archive",
+            desc: "display the contents of an OS image archive",
+            func: cmd_inspect_archive,
+            hide: false,
+            blank: true,
+        },
+        CommandInfo {
+            name: "verify-archive",
+            desc: "check the signature on an OS image archive",
+            func: cmd_verify_archive,
+            hide: false,
+            blank: false,
+        },
+        CommandInfo {
+            name: "diff-archive",
+            desc: "compare the contents of two OS image archives",
+            func: cmd_diff_archive,
+            hide: false,
+            blank: false,
+        },
+        CommandInfo {
+            name: "extract-archive",
+            desc: "unpack an OS image archive into a directory",
+            func: cmd_extract_archive,
+            hide: false,
+            blank: false,
+        },
+        CommandInfo {
+            name: "help",
+            desc: "display usage information",
+            /*
+             * No behaviour is required here.  The "help" command is a special case
+             * in the argument processing below.
+             */
+            func: |_: &CommandArg| Ok(()),
+            hide: false,
+            blank: true,
+        },
+    ];
+
+    let usage = |failure: bool| {
+        let mut out = String::new();
+        out += "Usage: helios [OPTIONS] COMMAND [OPTIONS] [ARGS...]\n\n";
+        for ci in handlers.iter() {
+            if ci.hide {
+                continue;
+            }
+
+            if ci.blank {
+                out += "\n";
+            }
+
+            out += &format!("    {:<16} {}\n", ci.name, ci.desc);
+        }
+        let msg = opts.usage(&out);
+        if failure {
+            eprintln!("{msg}");
+        } else {
+            println!("{msg}");
+        }
+    };
+
+    let res = match opts.parse(std::env::args_os().skip(1)) {
+        Ok(res) => res,
+        Err(e) => {
+            usage(true);
+            bail!("{e}");
+        }
+    };
+
+    if res.opt_present("help") {
+        usage(false);
+        return Ok(());
+    }
+
+    if res.free.is_empty() {
+        usage(true);
+        bail!("choose a command");
+    }
+
+    if res.free[0] == "help" {
+        usage(false);
+        return Ok(());
+    }
+
+    let args = res.free[1..].iter().map(|s| s.as_str()).collect::<Vec<_>>();
+
+    let verbosity = res.opt_count("v") as i32 - res.opt_count("q") as i32;
+    let lo = LogOptions {
+        format: match res.opt_str("log-format") {
+            Some(f) => f.parse()?,
+            None => LogFormat::Term,
+        },
+        level: match verbosity {
+            i32::MIN..=-3 => slog::Level::Critical,
+            -2 => slog::Level::Error,
+            -1 => slog::Level::Warning,
+            0 => slog::Level::Info,
+            1 => slog::Level::Debug,
+            2..=i32::MAX => slog::Level::Trace,
+        },
+        file: res.opt_str("log-file").map(PathBuf::from),
+    };
+    let log = init_log(&lo)?;
+
+    /*
+     * The paths to the tools we run may be overridden for this machine.
+     */
+    if let Ok(p) = top_path(&["config", "tools.toml"]) {
+        if exists_file(&p)? {
+            tools::load(&p)?;
+        }
+    }
+
+    for ci in handlers.iter() {
+        if ci.name != res.free[0] {
+            continue;
+        }
+
+        /*
+         * Keep a transcript of each command we run in a log directory for
+         * this invocation.
+         */
+        if let Ok(logs) = top_path(&["tmp", "logs"]) {
+            let fmt = format_description::parse(
+                "[year][month][day]T[hour][minute][second]Z",
+            )
+            .unwrap();
+            let now = OffsetDateTime::now_utc().format(&fmt).unwrap();
+            transcript::set_log_dir(logs.join(format!("{now}-{}", ci.name)));
+        }
+
+        let ca = CommandArg { log: &log, args: args.as_slice() };
+
+        let started = SystemTime::now();
+        let res = (ci.func)(&ca);
+
+        /*
+         * A command that fails part way through may not have put away the
+         * file hash cache, so make sure that what it learned is kept.
+         */
+        if let Err(e) = ensure::set_hash_cache(None) {
+            slog::warn!(log, "could not write file hash cache: {e}");
+        }
+
+        /*
+         * If any other programs were run, write a report on each of them and
+         * summarise where the time went.  The summary is for a person to read,
+         * so it stays out of the way of JSON logging and of a request for
+         * quiet; the report itself has the same information.
+         */
+        let records = report::records();
+        if !records.is_empty() {
+            if lo.format == LogFormat::Term
+                && lo.level.is_at_least(slog::Level::Info)
+            {
+                eprintln!("\n{}", report::summary(&records));
+            }
+            match transcript::log_dir().and_then(|dir| {
+                let Some(dir) = dir else {
+                    return Ok(None);
+                };
+                let p = dir.join("build-report.json");
+                report::write(&p, ci.name, &args, started, &res)?;
+                Ok(Some(p))
+            }) {
+                Ok(Some(p)) => info!(log, "build report: {}", p.display()),
+                Ok(None) => (),
+                Err(e) => slog::warn!(log, "could not write build report: {e}"),
+            }
+        }
+
+        return res;
+    }
+
+    usage(true);
+    bail!("command \"{}\" not understood", res.free[0]);
+}
+
+/*
+ * Extract a hash from a string produced by:
+ *    git describe --all --long --dirty
+ */
+fn extract_hash(s: &str) -> Option<&str> {
+    /*
+     * Look from the end for a column that appears to be the git hash.
+     */
+    s.split('-').rev().find_map(|col| {
+        /*
+         * Require column that starts with `g`; strip it off.
+         */
+        let suffix = col.strip_prefix('g')?;
+
         /*
          * Require suffix to be at least 7 chars and all ascii.
          */
@@ -2821,17 +4419,28 @@ fn rustup_install_toolchain<P: AsRef<Path>>(log: &Logger, p: P) -> Result<()> {
      * version.
      */
     info!(log, "checking rust toolchain is installed for {p:?}");
-    let out = Command::new("rustup")
-        .args(["show", "active-toolchain"])
-        .current_dir(p)
-        .output()?;
+    let out = ensure::output(
+        log,
+        Tool::Rustup
+            .command()
+            .args(["show", "active-toolchain"])
+            .current_dir(p),
+        ensure::Timeout::Network,
+        ensure::Retry::NEVER,
+    )?;
 
     if out.status.success() {
         let ver = String::from_utf8_lossy(&out.stdout).trim().to_string();
         info!(log, "rust toolchain for {p:?}: {ver:?}");
     } else {
         info!(log, "installing rust toolchain for {p:?}...");
-        ensure::run_in(log, p, &["rustup", "toolchain", "install"])?;
+        ensure::run_in(
+            log,
+            p,
+            &[Tool::Rustup.path().as_str(), "toolchain", "install"],
+            ensure::Timeout::Network,
+            ensure::Retry::NETWORK,
+        )?;
     }
 
     Ok(())
@@ -2845,3 +4454,833 @@ fn hash_extract() {
     assert_eq!(extract_hash("heads/master-0-g7f745e"), None);
     assert_eq!(extract_hash("heads/master-0"), None);
 }
+
+#[test]
+fn pkg_list_parse() {
+    let list = "\
+        pkg://helios-dev/system/kernel@0.5.11-2.0.22183:20240319T212116Z i--\n\
+        pkg://on-nightly/driver/network/e1000g@0.5.11-2.0.999999 i--\n";
+    let pkgs = parse_pkg_list(list).unwrap();
+    assert_eq!(pkgs.len(), 2);
+    assert_eq!(pkgs[0].publisher, "helios-dev");
+    assert_eq!(pkgs[0].name, "system/kernel");
+    assert_eq!(pkgs[0].version, "0.5.11-2.0.22183:20240319T212116Z");
+    assert_eq!(pkgs[1].name, "driver/network/e1000g");
+    assert!(parse_pkg_list("system/kernel@1.0").is_err());
+
+    let pubs = "\
+        PUBLISHER\tSTICKY\tSYSPUB\tENABLED\tTYPE\tSTATUS\tURI\tPROXY\n\
+        on-nightly\ttrue\tfalse\ttrue\torigin\tonline\tfile:///repo/\t-\n\
+        helios-dev\ttrue\tfalse\ttrue\torigin\tonline\thttps://pkg/\t-\n";
+    assert_eq!(
+        parse_pkg_publishers(pubs).unwrap(),
+        vec![
+            ("on-nightly".to_string(), "file:///repo/".to_string()),
+            ("helios-dev".to_string(), "https://pkg/".to_string()),
+        ]
+    );
+
+    let args =
+        format!("image arguments: {:#?}\n", &["-o", "/out", "-N", "a b"]);
+    assert_eq!(
+        parse_image_args(&args),
+        Some(vec!["-o".into(), "/out".into(), "-N".into(), "a b".into()])
+    );
+}
+
+#[test]
+fn diff_maps_changes() {
+    let map = |kv: &[(&str, &str)]| -> BTreeMap<String, String> {
+        kv.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    };
+    let a = map(&[("kernel", "1"), ("libc", "2"), ("zfs", "3")]);
+    let b = map(&[("libc", "2"), ("zfs", "4"), ("cpio", "5")]);
+
+    /*
+     * Removed and changed keys come first, in order, followed by the added
+     * keys.  Keys are padded to the width of the longest in either map.
+     */
+    assert_eq!(
+        diff_maps(&a, &b),
+        vec!["- kernel  1", "~ zfs     3 -> 4", "+ cpio    5"],
+    );
+    assert!(diff_maps(&b, &b).is_empty());
+}
+
+#[test]
+fn diff_archive() {
+    let dir = testutil::TempDir::new("diff-archive");
+    let mk = |name: &str, version: &str, extra: bool| {
+        let p = dir.join(name);
+        let a = testutil::os_archive(&p);
+        a.add_file_with_data(
+            format!("pkg://helios-dev/system/kernel@{version} i--\n")
+                .into_bytes(),
+            "pkg-list.txt",
+        )
+        .unwrap();
+        a.add_file_with_data(b"unix\n".to_vec(), "unix.z").unwrap();
+        if extra {
+            a.add_file_with_data(b"cpio\n".to_vec(), "cpio.z").unwrap();
+        }
+        a.finish().unwrap();
+        p
+    };
+    let a = mk("a.tar.gz", "1.0", false);
+    let b = mk("b.tar.gz", "2.0", true);
+
+    let log = testutil::log();
+    let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+    cmd_diff_archive(&CommandArg { log: &log, args: &[a, b] }).unwrap();
+    assert!(cmd_diff_archive(&CommandArg { log: &log, args: &[a] }).is_err());
+
+    let summary = |p: &str| {
+        let c = archive::Contents::read(Path::new(p)).unwrap();
+        ArchiveSummary::from_contents(&c).unwrap()
+    };
+    let sections = diff_summaries(&summary(a), &summary(b));
+    assert_eq!(
+        sections[3],
+        (
+            "packages",
+            vec!["~ system/kernel  1.0 (helios-dev) -> 2.0 (helios-dev)"
+                .to_string()],
+        ),
+    );
+
+    /*
+     * The files are compared by size and hash, which we need not spell out.
+     */
+    let changed: Vec<(&str, Vec<String>)> = sections
+        .into_iter()
+        .filter(|(_, lines)| !lines.is_empty())
+        .map(|(title, lines)| {
+            let lines = lines
+                .iter()
+                .map(|l| l.split_whitespace().take(2).collect::<Vec<_>>())
+                .map(|w| w.join(" "))
+                .collect();
+            (title, lines)
+        })
+        .collect();
+    assert_eq!(
+        changed,
+        vec![
+            ("packages", vec!["~ system/kernel".to_string()]),
+            (
+                "files",
+                vec![
+                    "~ SHA256SUMS".to_string(),
+                    "~ pkg-list.txt".to_string(),
+                    "+ cpio.z".to_string(),
+                ],
+            ),
+        ],
+    );
+}
+
+#[test]
+fn name_template_check() {
+    let none = HashMap::new();
+    let check = |t: &str| check_name_template(t, &none);
+    assert!(check("${user}@${host}: ${os_short_commit}").is_ok());
+    assert!(check("${usr}").is_err());
+    assert!(check("${date|strftime:%H}").is_err());
+    assert!(check(
+        "${git.illumos.short}${git.illumos.dirty?-dirty} ${env.BUILD_ID:-0}"
+    )
+    .is_ok());
+    assert!(check("${git.illumos}").is_err());
+    assert!(check("${git..short}").is_err());
+    assert!(check("${env.HOME}").is_err());
+
+    /*
+     * Optional tokens must be written to cope with being unset, unless we
+     * already know that they will have a value.
+     */
+    assert!(check("${git.illumos.dirty}").is_err());
+    assert!(check("${debug}").is_err());
+    assert!(check("${env.BUILD_ID}").is_err());
+    assert!(check("${debug?-debug}${recovery:-normal}").is_ok());
+    let known = [("debug", "debug"), ("env.BUILD_ID", "ci-1234")]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let check_known = |t: &str| check_name_template(t, &known);
+    assert!(check_known("${debug}-${env.BUILD_ID}").is_ok());
+    assert!(check_known("${recovery}").is_err());
+    assert!(check_known("${env.BUILD_ID|strftime:%Y}").is_err());
+
+    let exp = check("${git.pinprick.branch}${boards}").unwrap();
+    assert_eq!(
+        name_git_projects(&exp).into_iter().collect::<Vec<_>>(),
+        vec!["pinprick"]
+    );
+}
+
+/*
+ * Create a scratch repository with the directories that commands expect to
+ * find, and a release file for a current Helios system, and use it in place
+ * of the real one for the rest of the test.
+ */
+#[cfg(test)]
+fn fake_top(top: &Path) {
+    for d in ["config", "tmp", "packages", "projects"] {
+        std::fs::create_dir_all(top.join(d)).unwrap();
+    }
+    std::fs::write(top.join("os-release"), "ID=helios\nVERSION_ID=3\n")
+        .unwrap();
+    TEST_TOP.with(|t| *t.borrow_mut() = Some(top.to_path_buf()));
+}
+
+/*
+ * Add what "helios-build image" needs to a scratch repository: a board to
+ * build a ROM for, the projects that provide the programs it runs, and a
+ * dataset in which the image builder leaves the kernel.  The programs, and
+ * the system tools, just leave behind the files that the next step expects.
+ */
+#[cfg(test)]
+fn fake_image_top(top: &Path, bin: &Path) -> testutil::FakeTools {
+    fake_top(top);
+
+    let templates = top.join("image").join("templates").join("sled");
+    std::fs::create_dir_all(&templates).unwrap();
+    std::fs::write(
+        templates.join("targets.toml"),
+        "[gimlet]\nefs = \"gimlet.efs.json5\"\napp = \"gimlet.toml\"\n",
+    )
+    .unwrap();
+    let amd = top.join("image").join("amd");
+    std::fs::create_dir_all(&amd).unwrap();
+    std::fs::write(amd.join("gimlet.efs.json5"), "{}\n").unwrap();
+    std::fs::write(amd.join("gimlet.toml"), "\n").unwrap();
+    testutil::script(&top.join("image").join("mkcpio.sh"), "echo cpio >\"$2\"");
+
+    for (project, target, body) in [
+        ("image-builder", "debug", "exit 0"),
+        (
+            "bootserver/mkimage",
+            "release",
+            "while getopts i:N:o:O:s:z o; do\n\
+            case $o in o) out=$OPTARG ;; O) csum=$OPTARG ;; esac\n\
+            done\n\
+            echo image >\"$out\"\n\
+            printf '\\001\\002' >\"$csum\"",
+        ),
+        ("pinprick", "release", "cat \"$1\""),
+    ] {
+        let (project, command) =
+            project.split_once('/').unwrap_or((project, project));
+        let dir =
+            top.join("projects").join(project).join("target").join(target);
+        std::fs::create_dir_all(&dir).unwrap();
+        testutil::script(&dir.join(command), body);
+    }
+    for project in ["phbl", "amd-host-image-builder"] {
+        std::fs::create_dir_all(top.join("projects").join(project)).unwrap();
+    }
+
+    let mp = top.join("dataset");
+    let kernel = mp.join("work/sled/ramdisk/platform/oxide/kernel/amd64");
+    std::fs::create_dir_all(&kernel).unwrap();
+    std::fs::write(kernel.join("unix"), "unix\n").unwrap();
+    std::env::set_var("IMAGE_DATASET", "rpool/images/test");
+    std::env::set_var("SOURCE_DATE_EPOCH", "1700000000");
+
+    let fake = testutil::FakeTools::new(bin);
+    fake.add(
+        Tool::Zfs,
+        &format!("if [ \"$1\" = get ]; then echo '{}'; fi", mp.display()),
+    );
+    fake.add(
+        Tool::Git,
+        "echo '# branch.oid 0123456789abcdef0123456789abcdef01234567'\n\
+        echo '# branch.head master'",
+    );
+    fake.add(Tool::Pfexec, "exec \"$@\"");
+    fake.add(Tool::Pkg, "exit 0");
+    fake.add(Tool::Pkgrepo, "exit 0");
+    fake.add(Tool::Pkgrecv, "exit 0");
+    fake.add(
+        Tool::Cargo,
+        "while [ $# -gt 0 ]; do\n\
+        if [ \"$1\" = --image ]; then echo rom >\"$2\"; fi\n\
+        shift\n\
+        done\n\
+        exit 0",
+    );
+    fake.install();
+    fake
+}
+
+#[test]
+fn fake_tools() {
+    let dir = testutil::TempDir::new("fake-tools");
+    let fake = testutil::FakeTools::new(&dir.join("bin"));
+    fake.add(Tool::Pkgrepo, "exit 0");
+    fake.add(Tool::Psrinfo, "echo 64");
+    fake.add(
+        Tool::Zfs,
+        "echo \"cannot open '$4': dataset does not exist\" >&2\nexit 1",
+    );
+    fake.install();
+
+    let log = testutil::log();
+    let repo = dir.join("repo");
+    create_ips_repo(&log, &repo, "helios-dev", false).unwrap();
+    assert_eq!(ncpus().unwrap(), 64);
+    assert!(!zfs::dataset_exists("rpool/nothing").unwrap());
+
+    let repo = repo.to_str().unwrap();
+    assert_eq!(
+        fake.calls(),
+        format!(
+            "pkgrepo create {repo}\n\
+            pkgrepo add-publisher -s {repo} helios-dev\n\
+            psrinfo -t\n\
+            zfs list -Ho name rpool/nothing\n"
+        ),
+    );
+
+    /*
+     * Each of those commands is in the build report, too.
+     */
+    let bin = dir.join("bin");
+    let bin = bin.to_str().unwrap();
+    let recorded: Vec<String> = report::records()
+        .into_iter()
+        .filter(|r| r.args[0].starts_with(bin))
+        .map(|r| r.args[1..].join(" "))
+        .collect();
+    assert_eq!(
+        recorded,
+        vec![
+            format!("create {repo}"),
+            format!("add-publisher -s {repo} helios-dev"),
+            "-t".to_string(),
+            "list -Ho name rpool/nothing".to_string(),
+        ],
+    );
+}
+
+#[test]
+fn setup_end_to_end() {
+    let _settings =
+        testutil::ENSURE_SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+
+    let dir = testutil::TempDir::new("setup");
+    let top = dir.join("top");
+    fake_top(&top);
+    std::fs::write(
+        top.join("config").join("projects.toml"),
+        "[project.tool]\n\
+        url = \"https://example.com/tool.git\"\n\
+        rev = \"v1\"\n\
+        site_sh = true\n\
+        cargo_build = true\n",
+    )
+    .unwrap();
+
+    /*
+     * A clone leaves behind the directory it was asked to create, as the real
+     * one would, with somewhere to put the site.sh file.
+     */
+    let fake = testutil::FakeTools::new(&dir.join("bin"));
+    fake.add(
+        Tool::Git,
+        "if [ \"$1\" = clone ]; then\n\
+        for a; do last=$a; done\n\
+        mkdir -p \"$last/lib\"\n\
+        fi",
+    );
+    fake.add(Tool::Pkgrepo, "exit 0");
+    fake.add(Tool::Rustup, "echo stable");
+    fake.add(Tool::Cargo, "exit 0");
+    fake.install();
+
+    let log = testutil::log();
+    cmd_setup(&CommandArg { log: &log, args: &[] }).unwrap();
+
+    /*
+     * Whether git is asked to show progress depends on where the output of
+     * the tests is going.
+     */
+    let calls = fake.calls().replace(" --progress", "");
+    let packages = top.join("packages");
+    let (t, p) = (top.display(), packages.display());
+    assert_eq!(
+        calls,
+        format!(
+            "git clone --recurse-submodules https://example.com/tool.git \
+            {t}/projects/tool\n\
+            git fetch origin v1\n\
+            git checkout v1\n\
+            git submodule update --recursive\n\
+            pkgrepo create {p}/os\n\
+            pkgrepo add-publisher -s {p}/os helios\n\
+            pkgrepo create {p}/other\n\
+            pkgrepo add-publisher -s {p}/other helios\n\
+            pkgrepo create {p}/combined\n\
+            pkgrepo add-publisher -s {p}/combined helios\n\
+            rustup show active-toolchain\n\
+            cargo build --locked --release\n"
+        ),
+    );
+
+    let site_sh = std::fs::read_to_string(
+        top.join("projects").join("tool").join("lib").join("site.sh"),
+    )
+    .unwrap();
+    assert!(site_sh.contains("PKGPUBLISHER=helios\n"), "{site_sh}");
+    assert!(site_sh.contains("RELVER=3\n"), "{site_sh}");
+    assert_eq!(
+        std::fs::read_link(top.join("packages").join("os-deps.mogrify"))
+            .unwrap(),
+        Path::new("../tools/packages/os-deps.mogrify"),
+    );
+    assert!(top.join("packages").join("publisher.mogrify").exists());
+}
+
+#[test]
+fn merge_illumos_end_to_end() {
+    let _settings =
+        testutil::ENSURE_SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+
+    let dir = testutil::TempDir::new("merge-illumos");
+    let top = dir.join("top");
+    fake_top(&top);
+    let fake = testutil::FakeTools::new(&dir.join("bin"));
+    for tool in [Tool::Pkgrepo, Tool::Pkgmerge, Tool::Pkgrecv] {
+        fake.add(tool, "exit 0");
+    }
+    fake.install();
+
+    let log = testutil::log();
+    cmd_merge_illumos(&CommandArg { log: &log, args: &["-p", "helios-test"] })
+        .unwrap();
+
+    let (t, gate) = (top.display(), top.join("projects/illumos"));
+    let (m, g) = (format!("{t}/tmp/illumos/nightly-merged"), gate.display());
+    assert_eq!(
+        fake.calls(),
+        format!(
+            "pkgrepo create {m}\n\
+            pkgrepo add-publisher -s {m} on-nightly\n\
+            pkgmerge -d {m} \
+            -s debug.illumos=false,{g}/packages/i386/nightly-nd/repo.redist/ \
+            -s debug.illumos=true,{g}/packages/i386/nightly/repo.redist/\n\
+            pkgrecv -s {m} -d {t}/packages/os \
+            --mog-file {t}/tmp/illumos/custom-publisher.mogrify \
+            --mog-file {t}/packages/os-conflicts.mogrify \
+            --mog-file {t}/packages/os-deps.mogrify -m latest *\n\
+            pkgrepo refresh -s {t}/packages/os\n"
+        ),
+    );
+
+    /*
+     * The custom publisher transform is only needed while receiving the
+     * packages.
+     */
+    assert!(!top.join("tmp/illumos/custom-publisher.mogrify").exists());
+}
+
+#[test]
+fn onu_end_to_end() {
+    let _settings =
+        testutil::ENSURE_SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+
+    let dir = testutil::TempDir::new("onu");
+    let top = dir.join("top");
+    fake_top(&top);
+    let fake = testutil::FakeTools::new(&dir.join("bin"));
+    for tool in [Tool::Pkgrepo, Tool::Pkgrecv, Tool::Pfexec] {
+        fake.add(tool, "exit 0");
+    }
+    fake.install();
+
+    let log = testutil::log();
+    let gate = dir.join("gate");
+    std::fs::create_dir(&gate).unwrap();
+    let g = gate.to_str().unwrap();
+    let repo = |debug: &str| {
+        let r = format!("{}/tmp/onu.gate-gate/repo.redist", top.display());
+        format!(
+            "pkgrepo create {r}\n\
+            pkgrepo add-publisher -s {r} on-nightly\n\
+            pkgrecv -s {g}/packages/i386/{debug}/repo.redist -d {r} \
+            --mog-file {t}/packages/os-conflicts.mogrify \
+            --mog-file {t}/packages/os-deps.mogrify -m latest *\n\
+            pkgrepo refresh -s {r}\n",
+            t = top.display(),
+        )
+    };
+
+    /*
+     * With -P, we stop once the packages are ready.
+     */
+    cmd_illumos_onu(&CommandArg { log: &log, args: &["-P", "-d", "-g", g] })
+        .unwrap();
+    assert_eq!(fake.calls(), repo("nightly"));
+
+    cmd_illumos_onu(&CommandArg { log: &log, args: &["-t", "be", "-g", g] })
+        .unwrap();
+    assert_eq!(
+        fake.calls(),
+        format!(
+            "{}{}pfexec {g}/usr/src/tools/proto/root_i386-nd/opt/onbld/bin/onu \
+            -v -d {}/tmp/onu.gate-gate -t be\n",
+            repo("nightly"),
+            repo("nightly-nd"),
+            top.display(),
+        ),
+    );
+
+    assert!(cmd_illumos_onu(&CommandArg {
+        log: &log,
+        args: &["-P", "-t", "be"]
+    })
+    .is_err());
+}
+
+#[test]
+fn image_end_to_end() {
+    let _settings =
+        testutil::ENSURE_SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+
+    let dir = testutil::TempDir::new("image");
+    let top = dir.join("top");
+    let fake = fake_image_top(&top, &dir.join("bin"));
+
+    let log = testutil::log();
+    let out = dir.join("out");
+    cmd_image(&CommandArg {
+        log: &log,
+        args: &["-N", "test-${relver}", "-o", out.to_str().unwrap()],
+    })
+    .unwrap();
+
+    /*
+     * Check that each step was run, in order, with the templates we expect.
+     */
+    let calls = fake.calls();
+    let steps: Vec<String> = calls
+        .lines()
+        .map(|l| {
+            let w: Vec<&str> = l.split(' ').collect();
+            match w[0] {
+                "pfexec" if w[2] == "build" => {
+                    let n = w.iter().position(|a| *a == "-n").unwrap();
+                    format!("builder {}", w[n + 1..].join(" "))
+                }
+                "pfexec" => "pfexec pkg".to_string(),
+                _ => format!("{} {}", w[0], w[1]),
+            }
+        })
+        .collect();
+    assert_eq!(
+        steps,
+        vec![
+            "zfs list",
+            "zfs get",
+            "pkgrepo create",
+            "pkgrepo add-publisher",
+            "pkgrecv -s",
+            "builder ramdisk-01-os --fullreset",
+            "builder ramdisk-02-trim",
+            "builder zfs",
+            "git status",
+            "git status",
+            "git status",
+            "git status",
+            "git status",
+            "pfexec pkg",
+            "pkg -R",
+            "pfexec pkg",
+            "pkg -R",
+            "cargo xtask",
+            "cargo xtask",
+        ],
+    );
+
+    let mut files: Vec<String> = std::fs::read_dir(&out)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_str().unwrap().to_string())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            "SHA256SUMS",
+            "cpio",
+            "cpio.z",
+            "gimlet.rom",
+            "os.tar.gz",
+            "unix.z",
+            "zfs.img",
+        ],
+    );
+    assert_eq!(std::fs::read_to_string(out.join("unix.z")).unwrap(), "unix\n");
+
+    /*
+     * The manifest in the output directory must describe the files that are
+     * actually there.
+     */
+    let sums = std::fs::read_to_string(out.join(archive::SHA256SUMS)).unwrap();
+    let sums = archive::parse_sums(&sums).unwrap();
+    assert_eq!(
+        sums.keys().collect::<Vec<_>>(),
+        vec!["cpio.z", "gimlet.rom", "unix.z", "zfs.img"],
+    );
+    for (name, hash) in sums {
+        assert_eq!(archive::file_sha256(&out.join(name)).unwrap(), hash);
+    }
+
+    let c = archive::Contents::read(&out.join("os.tar.gz")).unwrap();
+    assert_eq!(c.metadata["name"], "test-3");
+    assert_eq!(c.metadata["checksum"], "0102");
+    let names: Vec<&str> = c.entries.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(
+        names,
+        vec![
+            "image-args.txt",
+            "git-status-amd-host-image-builder.txt",
+            "git-status-bootserver.txt",
+            "git-status-image-builder.txt",
+            "git-status-phbl.txt",
+            "git-status-pinprick.txt",
+            "pkg-publishers.txt",
+            "pkg-list.txt",
+            "zfs.img",
+            "unix.z",
+            "cpio.z",
+            "gimlet.rom",
+            "rom",
+            archive::SHA256SUMS,
+        ],
+    );
+    assert_eq!(c.links["roms/gimlet.rom"], "../gimlet.rom");
+}
+
+#[test]
+fn rom_archive_reproducible() {
+    let _settings =
+        testutil::ENSURE_SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+
+    /*
+     * Building the same ROM from two different checkouts must produce the
+     * same ROM archive.
+     */
+    let dir = testutil::TempDir::new("rom-archive");
+    let log = testutil::log();
+    let mut archives = Vec::new();
+    for name in ["one", "two"] {
+        let top = dir.join(name).join("top");
+        fake_image_top(&top, &dir.join(name).join("bin"));
+
+        let out = dir.join(name).join("out");
+        cmd_image(&CommandArg {
+            log: &log,
+            args: &[
+                "-N",
+                "test",
+                "-o",
+                out.to_str().unwrap(),
+                "-X",
+                "install",
+                "--rom-archives",
+            ],
+        })
+        .unwrap();
+        archives.push(out.join("rom-gimlet.tar.gz"));
+    }
+
+    let c = archive::Contents::read(&archives[0]).unwrap();
+    assert_eq!(c.metadata["efs"], "image/amd/gimlet.efs.json5");
+    assert_eq!(c.metadata["app"], "image/amd/gimlet.toml");
+    assert_eq!(
+        std::fs::read(&archives[0]).unwrap(),
+        std::fs::read(&archives[1]).unwrap(),
+    );
+}
+
+#[test]
+fn rom_config_ddr_limit() {
+    let dir = testutil::TempDir::new("rom-config");
+    let out = dir.join("rom.efs.json");
+
+    /*
+     * Only the frequency limit token in the memory controller entry of the
+     * APCB backup is to change; the same token elsewhere is left alone.
+     */
+    let apcb = |limit: &str| {
+        format!(
+            "source: {{ ApcbJson: {{ entries: [
+                {{
+                    header: {{
+                        group_id: 0x3000, entry_id: 0x0004, instance_id: 0,
+                    }},
+                    tokens: [
+                        {{ Dword: {{ MemBusFrequencyLimit: \"{limit}\" }} }},
+                        {{ Dword: {{ MemClkFreq: \"Ddr3200\" }} }},
+                        {{ Bool: {{ MemEnableParity: true }} }},
+                    ],
+                }},
+                {{
+                    header: {{
+                        group_id: 0x3000, entry_id: 0x0004, instance_id: 1,
+                    }},
+                    tokens: [
+                        {{ Dword: {{ MemBusFrequencyLimit: \"Ddr3200\" }} }},
+                    ],
+                }},
+            ] }} }}"
+        )
+    };
+    let input = |limit: &str, other: &str| {
+        format!(
+            "// JSON5, as for amd-host-image-builder\n\
+            {{ bhd: {{ BhdDirectory: {{ entries: [
+                {{ target: {{ type: \"ApcbBackup\" }}, {} }},
+                {{ target: {{ type: \"Apcb\" }}, {} }},
+            ] }} }} }}",
+            apcb(limit),
+            apcb(other),
+        )
+    };
+
+    let v: serde_json::Value =
+        json5::from_str(&input("Ddr3200", "Ddr3200")).unwrap();
+    mk_rom_config(v, &out, 2133).unwrap();
+    let got: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&out).unwrap()).unwrap();
+    let want: serde_json::Value =
+        json5::from_str(&input("Ddr2133", "Ddr3200")).unwrap();
+    assert_eq!(got, want);
+
+    let e = mk_rom_config(serde_json::json!({ "bhd": {} }), &out, 2133)
+        .unwrap_err();
+    assert_eq!(e.to_string(), "could not find BhdDirectory");
+}
+
+#[test]
+fn genproto_steps() {
+    let dir = testutil::TempDir::new("genproto");
+    let proto = dir.join("proto");
+    let template = dir.join("genproto.json");
+    std::fs::create_dir_all(proto.join("usr/bin")).unwrap();
+    std::fs::create_dir_all(proto.join("etc")).unwrap();
+    std::fs::write(proto.join("usr/bin/tool"), "#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(
+        proto.join("usr/bin/tool"),
+        std::fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+    std::fs::write(proto.join("etc/motd"), "hello\n").unwrap();
+    std::fs::set_permissions(
+        proto.join("etc/motd"),
+        std::fs::Permissions::from_mode(0o644),
+    )
+    .unwrap();
+    std::os::unix::fs::symlink("../usr/bin/tool", proto.join("etc/tool"))
+        .unwrap();
+    for d in ["usr", "usr/bin", "etc"] {
+        std::fs::set_permissions(
+            proto.join(d),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+    }
+
+    genproto(&proto, &template).unwrap();
+
+    /*
+     * The order of the steps follows the directory walk, so compare them by
+     * the path each one is for.
+     */
+    let v: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&template).unwrap()).unwrap();
+    let steps: BTreeMap<String, serde_json::Value> = v["steps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| {
+            let p = ["dir", "file", "link"]
+                .iter()
+                .find_map(|k| s[k].as_str())
+                .unwrap();
+            (p.to_string(), s.clone())
+        })
+        .collect();
+    assert_eq!(
+        steps,
+        [
+            serde_json::json!({
+                "t": "ensure_dir", "dir": "/usr",
+                "owner": "root", "group": "sys", "mode": "755",
+            }),
+            serde_json::json!({
+                "t": "ensure_dir", "dir": "/usr/bin",
+                "owner": "root", "group": "bin", "mode": "755",
+            }),
+            serde_json::json!({
+                "t": "ensure_file", "file": "/usr/bin/tool",
+                "extsrc": "usr/bin/tool",
+                "owner": "root", "group": "root", "mode": "755",
+            }),
+            serde_json::json!({
+                "t": "ensure_dir", "dir": "/etc",
+                "owner": "root", "group": "sys", "mode": "755",
+            }),
+            serde_json::json!({
+                "t": "ensure_file", "file": "/etc/motd", "extsrc": "etc/motd",
+                "owner": "root", "group": "root", "mode": "644",
+            }),
+            serde_json::json!({
+                "t": "ensure_symlink", "link": "/etc/tool",
+                "target": "../usr/bin/tool",
+                "owner": "root", "group": "root",
+            }),
+        ]
+        .into_iter()
+        .map(|s| {
+            let p = ["dir", "file", "link"]
+                .iter()
+                .find_map(|k| s[k].as_str())
+                .unwrap()
+                .to_string();
+            (p, s)
+        })
+        .collect::<BTreeMap<_, _>>(),
+    );
+
+    /*
+     * On illumos, /bin is a link to /usr/bin, so a proto area must not have a
+     * directory there.
+     */
+    std::fs::create_dir_all(proto.join("bin")).unwrap();
+    assert!(genproto(&proto, &template).is_err());
+}
+
+#[test]
+fn publishers_origins() {
+    let mut p = Publishers::default();
+    assert_eq!(p.display(), "");
+
+    p.append_origin("helios-dev", "https://pkg.example.com/helios/2/dev/");
+    p.append_origin("on-nightly", "file:///ws/packages/repo");
+    p.append_origin("helios-dev", "https://mirror.example.com/helios/");
+    p.append_origin("helios-dev", "https://pkg.example.com/helios/2/dev/");
+
+    /*
+     * Publishers stay in the order they were first named, and each origin is
+     * listed once, in the order it was given.
+     */
+    assert!(p.has_publisher("on-nightly"));
+    assert!(!p.has_publisher("helios"));
+    assert_eq!(
+        p.display(),
+        "helios-dev={ https://pkg.example.com/helios/2/dev/ \
+        https://mirror.example.com/helios/ }, \
+        on-nightly={ file:///ws/packages/repo }",
+    );
+}
@@ -0,0 +1,169 @@
+/*
+ * Copyright 2026 Oxide Computer Company
+ */
+
+/*
+ * Helios is only built on illumos systems, but most of this tool is portable
+ * and its tests can be run elsewhere.  The few interfaces that only exist on
+ * illumos are wrapped here.  On other systems, each behaves as it would on an
+ * illumos system without the relevant configuration; e.g., a process outside
+ * of any zone is in the global zone, and a user without an entry in
+ * user_attr(5) has no attributes.
+ */
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+unsafe fn errno_location() -> *mut c_int {
+    libc::___errno()
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn errno_location() -> *mut c_int {
+    libc::__errno_location()
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+unsafe fn errno_location() -> *mut c_int {
+    libc::__error()
+}
+
+pub fn errno() -> c_int {
+    unsafe { *errno_location() }
+}
+
+pub fn clear_errno() {
+    unsafe { *errno_location() = 0 };
+}
+
+/**
+ * The login age and comment fields of a passwd entry are only present on
+ * illumos.
+ */
+#[cfg(target_os = "illumos")]
+pub fn pw_age_comment(pw: &libc::passwd) -> (*const c_char, *const c_char) {
+    (pw.pw_age as *const c_char, pw.pw_comment as *const c_char)
+}
+
+#[cfg(not(target_os = "illumos"))]
+pub fn pw_age_comment(_pw: &libc::passwd) -> (*const c_char, *const c_char) {
+    (std::ptr::null(), std::ptr::null())
+}
+
+#[cfg(target_os = "illumos")]
+mod illumos {
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int};
+
+    #[repr(C)]
+    pub struct Kv {
+        key: *const c_char,
+        value: *const c_char,
+    }
+
+    impl Kv {
+        pub fn name(&self) -> &CStr {
+            unsafe { CStr::from_ptr(self.key) }
+        }
+
+        pub fn value(&self) -> &CStr {
+            unsafe { CStr::from_ptr(self.value) }
+        }
+    }
+
+    #[repr(C)]
+    pub struct Kva {
+        length: c_int,
+        data: *const Kv,
+    }
+
+    impl Kva {
+        pub fn values(&self) -> &[Kv] {
+            unsafe {
+                std::slice::from_raw_parts(self.data, self.length as usize)
+            }
+        }
+    }
+
+    #[repr(C)]
+    pub struct UserAttrRaw {
+        pub name: *mut c_char,
+        pub qualifier: *mut c_char,
+        pub res1: *mut c_char,
+        pub res2: *mut c_char,
+        pub attr: *mut Kva,
+    }
+
+    #[link(name = "secdb")]
+    extern "C" {
+        pub fn getusernam(buf: *const c_char) -> *mut UserAttrRaw;
+        pub fn free_userattr(userattr: *mut UserAttrRaw);
+    }
+
+    #[link(name = "c")]
+    extern "C" {
+        pub fn getzoneid() -> i32;
+        pub fn getzonenamebyid(id: i32, buf: *mut u8, buflen: usize) -> isize;
+    }
+}
+
+/**
+ * Look up the attributes for a user in user_attr(5).  Attributes that are not
+ * valid UTF-8 are skipped.
+ */
+#[cfg(target_os = "illumos")]
+pub fn user_attr(name: &CStr) -> Option<HashMap<String, String>> {
+    let ua = unsafe { illumos::getusernam(name.as_ptr()) };
+    if ua.is_null() {
+        return None;
+    }
+
+    let mut out = HashMap::new();
+    for kv in unsafe { (*(*ua).attr).values() } {
+        if let (Ok(k), Ok(v)) = (kv.name().to_str(), kv.value().to_str()) {
+            out.insert(k.to_string(), v.to_string());
+        }
+    }
+
+    unsafe { illumos::free_userattr(ua) };
+
+    Some(out)
+}
+
+#[cfg(not(target_os = "illumos"))]
+pub fn user_attr(_name: &CStr) -> Option<HashMap<String, String>> {
+    None
+}
+
+#[cfg(target_os = "illumos")]
+pub fn zoneid() -> i32 {
+    unsafe { illumos::getzoneid() }
+}
+
+#[cfg(not(target_os = "illumos"))]
+pub fn zoneid() -> i32 {
+    0 /* GLOBAL_ZONEID */
+}
+
+#[cfg(target_os = "illumos")]
+pub fn zonename() -> String {
+    let buf = unsafe {
+        let mut buf: [u8; 64] = std::mem::zeroed(); /* ZONENAME_MAX */
+
+        let sz = illumos::getzonenamebyid(zoneid(), buf.as_mut_ptr(), 64);
+        if !(0..=64).contains(&sz) {
+            eprintln!("getzonenamebyid failure");
+            std::process::exit(100);
+        }
+
+        Vec::from(&buf[0..sz as usize])
+    };
+    CStr::from_bytes_with_nul(&buf).unwrap().to_str().unwrap().to_string()
+}
+
+#[cfg(not(target_os = "illumos"))]
+pub fn zonename() -> String {
+    "global".to_string()
+}
@@ -0,0 +1,185 @@
+/*
+ * Copyright 2026 Oxide Computer Company
+ */
+
+use anyhow::Result;
+use serde::Serialize;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::transcript::timestamp;
+
+/**
+ * A record of a single process that was run during a command, for the build
+ * report.
+ */
+#[derive(Clone, Serialize)]
+pub struct ExecRecord {
+    pub args: Vec<String>,
+    pub directory: Option<String>,
+    pub started: String,
+    pub finished: String,
+    pub duration_secs: f64,
+    pub status: String,
+    pub success: bool,
+    pub user_cpu_secs: Option<f64>,
+    pub system_cpu_secs: Option<f64>,
+    /*
+     * The maximum resident set size, in kilobytes.  Not every system reports
+     * this; e.g., illumos does not.
+     */
+    pub max_rss_kb: Option<u64>,
+    pub transcript: Option<String>,
+}
+
+fn secs(tv: &libc::timeval) -> f64 {
+    tv.tv_sec as f64 + tv.tv_usec as f64 / 1_000_000.0
+}
+
+impl ExecRecord {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        args: &[&OsStr],
+        directory: Option<&Path>,
+        started: SystemTime,
+        finished: SystemTime,
+        duration: Duration,
+        status: String,
+        success: bool,
+        rusage: Option<&libc::rusage>,
+        transcript: Option<&Path>,
+    ) -> ExecRecord {
+        ExecRecord {
+            args: args
+                .iter()
+                .map(|a| a.to_string_lossy().to_string())
+                .collect(),
+            directory: directory.map(|d| d.display().to_string()),
+            started: timestamp(started),
+            finished: timestamp(finished),
+            duration_secs: duration.as_secs_f64(),
+            status,
+            success,
+            user_cpu_secs: rusage.map(|ru| secs(&ru.ru_utime)),
+            system_cpu_secs: rusage.map(|ru| secs(&ru.ru_stime)),
+            max_rss_kb: rusage
+                .map(|ru| ru.ru_maxrss as u64)
+                .filter(|rss| *rss > 0),
+            transcript: transcript.map(|t| t.display().to_string()),
+        }
+    }
+}
+
+/*
+ * The processes that have been run so far by this invocation of the tool.
+ */
+static RECORDS: Mutex<Vec<ExecRecord>> = Mutex::new(Vec::new());
+
+pub fn record(r: ExecRecord) {
+    RECORDS.lock().unwrap().push(r);
+}
+
+pub fn records() -> Vec<ExecRecord> {
+    RECORDS.lock().unwrap().clone()
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    command: &'a str,
+    args: &'a [&'a str],
+    started: String,
+    finished: String,
+    duration_secs: f64,
+    result: String,
+    processes: &'a [ExecRecord],
+}
+
+/**
+ * Write the build report, which describes each process that was run by a
+ * command, as JSON.
+ */
+pub fn write(
+    p: &Path,
+    command: &str,
+    args: &[&str],
+    started: SystemTime,
+    result: &Result<()>,
+) -> Result<()> {
+    let finished = SystemTime::now();
+    let processes = records();
+
+    let report = Report {
+        command,
+        args,
+        started: timestamp(started),
+        finished: timestamp(finished),
+        duration_secs: finished
+            .duration_since(started)
+            .unwrap_or_default()
+            .as_secs_f64(),
+        result: match result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => format!("error: {e}"),
+        },
+        processes: &processes,
+    };
+
+    let mut buf = serde_json::to_vec_pretty(&report)?;
+    buf.push(b'\n');
+    std::fs::write(p, buf)?;
+    Ok(())
+}
+
+fn duration(secs: f64) -> String {
+    if secs < 60.0 {
+        return format!("{secs:.1}s");
+    }
+
+    let secs = secs.round() as u64;
+    if secs >= 3600 {
+        format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+/**
+ * Produce a table of the processes that were run, with the time and memory
+ * each one used.
+ */
+pub fn summary(records: &[ExecRecord]) -> String {
+    const WIDTH: usize = 60;
+
+    let mut out = format!(
+        "{:>3} {:>10} {:>10} {:>10} {:>9} {:<4} COMMAND\n",
+        "#", "ELAPSED", "USER", "SYS", "MAXRSS", "OK"
+    );
+    let mut total = 0.0;
+
+    for (i, r) in records.iter().enumerate() {
+        total += r.duration_secs;
+
+        let mut cmd = r.args.join(" ");
+        if cmd.chars().count() > WIDTH {
+            cmd = cmd.chars().take(WIDTH - 3).collect::<String>() + "...";
+        }
+
+        out += &format!(
+            "{:>3} {:>10} {:>10} {:>10} {:>9} {:<4} {}\n",
+            i + 1,
+            duration(r.duration_secs),
+            r.user_cpu_secs.map(duration).unwrap_or_else(|| "-".into()),
+            r.system_cpu_secs.map(duration).unwrap_or_else(|| "-".into()),
+            r.max_rss_kb
+                .map(|kb| format!("{}M", kb / 1024))
+                .unwrap_or_else(|| "-".into()),
+            if r.success { "yes" } else { "no" },
+            cmd,
+        );
+    }
+
+    out += &format!("{:>3} {:>10}\n", "", duration(total));
+    out
+}
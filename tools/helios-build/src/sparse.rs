@@ -0,0 +1,195 @@
+/*
+ * Copyright 2026 Oxide Computer Company
+ */
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+
+/*
+ * Data regions in a sparse tar entry must be a multiple of the tar block size
+ * in length, except for the last.  When looking for runs of zeroes in a file
+ * that has no holes on disk, we examine it in larger blocks than that, so as
+ * to keep the list of regions reasonably short.
+ */
+const ALIGN: u64 = 512;
+const SCAN_BLOCK: usize = 64 * 1024;
+
+static ZEROES: [u8; SCAN_BLOCK] = [0; SCAN_BLOCK];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+/*
+ * Add a region to the list, widening it out to block boundaries, and merging
+ * it with the previous region if they now touch.
+ */
+fn push_region(out: &mut Vec<Region>, start: u64, end: u64, len: u64) {
+    let start = start - start % ALIGN;
+    let end = (end.saturating_add(ALIGN - 1) / ALIGN * ALIGN).min(len);
+
+    if let Some(prev) = out.last_mut() {
+        if prev.end() >= start {
+            prev.length = end.max(prev.end()) - prev.offset;
+            return;
+        }
+    }
+
+    out.push(Region { offset: start, length: end - start });
+}
+
+/**
+ * Ask the file system where the data in a file is, using SEEK_DATA and
+ * SEEK_HOLE.  Returns None if the file system does not support the query.
+ */
+fn seek_regions(f: &File, len: u64) -> Result<Option<Vec<Region>>> {
+    let fd = f.as_raw_fd();
+
+    let mut out = Vec::new();
+    let mut off = 0;
+    while off < len {
+        let data =
+            unsafe { libc::lseek(fd, off as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let e = std::io::Error::last_os_error();
+            match e.raw_os_error() {
+                /*
+                 * There is no more data between this offset and the end of
+                 * the file.
+                 */
+                Some(libc::ENXIO) => break,
+                Some(libc::EINVAL) | Some(libc::ENOTSUP) => return Ok(None),
+                _ => return Err(e.into()),
+            }
+        }
+
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        push_region(&mut out, data as u64, hole as u64, len);
+        off = hole as u64;
+    }
+
+    Ok(Some(out))
+}
+
+/**
+ * Read the whole file, looking for blocks that contain only zeroes.
+ */
+fn scan_regions(f: &mut File, len: u64) -> Result<Vec<Region>> {
+    let mut out = Vec::new();
+    let mut buf = vec![0u8; SCAN_BLOCK];
+
+    f.seek(SeekFrom::Start(0))?;
+    let mut off = 0;
+    while off < len {
+        let n = (len - off).min(SCAN_BLOCK as u64) as usize;
+        f.read_exact(&mut buf[..n])?;
+
+        if buf[..n].iter().any(|b| *b != 0) {
+            push_region(&mut out, off, off + n as u64, len);
+        }
+        off += n as u64;
+    }
+
+    Ok(out)
+}
+
+/**
+ * Determine which regions of a file contain data, and which can be stored as
+ * holes.  We first ask the file system about holes; if it cannot tell us, or
+ * if the file has none, we scan the contents for blocks of zeroes instead.
+ * Returns None if the file would not benefit from being stored sparsely.
+ */
+pub fn data_regions(f: &mut File) -> Result<Option<Vec<Region>>> {
+    let len = f.metadata()?.len();
+    let dense = |r: &[Region]| r == [Region { offset: 0, length: len }];
+
+    let regions = match seek_regions(f, len)? {
+        Some(r) if !dense(&r) => r,
+        _ => scan_regions(f, len)?,
+    };
+
+    if len == 0 || dense(&regions) {
+        Ok(None)
+    } else {
+        Ok(Some(regions))
+    }
+}
+
+/**
+ * Read only the data regions of a sparse file, in order.  As the data passes
+ * through, the contents of the whole file, including the holes, are hashed.
+ */
+pub struct SparseReader<'a> {
+    f: File,
+    regions: &'a [Region],
+    len: u64,
+    pos: u64,
+    left: u64,
+    hasher: Sha256,
+}
+
+impl<'a> SparseReader<'a> {
+    pub fn new(f: File, regions: &'a [Region], len: u64) -> SparseReader<'a> {
+        SparseReader { f, regions, len, pos: 0, left: 0, hasher: Sha256::new() }
+    }
+
+    fn hash_zeroes(&mut self, upto: u64) {
+        while self.pos < upto {
+            let n = (upto - self.pos).min(SCAN_BLOCK as u64);
+            self.hasher.update(&ZEROES[..n as usize]);
+            self.pos += n;
+        }
+    }
+
+    /**
+     * Return the SHA-256 hash of the entire contents of the file.
+     */
+    pub fn finish(mut self) -> sha2::digest::Output<Sha256> {
+        self.hash_zeroes(self.len);
+        self.hasher.finalize()
+    }
+}
+
+impl Read for SparseReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.left == 0 {
+            let Some((r, rest)) = self.regions.split_first() else {
+                return Ok(0);
+            };
+            self.regions = rest;
+
+            self.hash_zeroes(r.offset);
+            self.f.seek(SeekFrom::Start(r.offset))?;
+            self.left = r.length;
+        }
+
+        let n = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        let n = self.f.read(&mut buf[..n])?;
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "file shrank while it was being archived",
+            ));
+        }
+
+        self.hasher.update(&buf[..n]);
+        self.pos += n as u64;
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
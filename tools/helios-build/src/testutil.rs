@@ -0,0 +1,125 @@
+/*
+ * Copyright 2026 Oxide Computer Company
+ */
+
+/*
+ * Fixtures shared by the tests in each module.
+ */
+
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use crate::archive::Archive;
+use crate::ensure::{self, CommandRunner};
+use crate::tools::Tool;
+
+/**
+ * A scratch directory for a single test, which is removed again when the test
+ * is finished with it, whether or not the test passed.
+ */
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir()
+            .join(format!("helios-build-{name}.{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, p: P) -> PathBuf {
+        self.0.join(p)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/*
+ * The dry run setting and the hash cache belong to the whole process, so the
+ * tests that use them must take turns.
+ */
+pub static ENSURE_SETTINGS: Mutex<()> = Mutex::new(());
+
+pub fn log() -> Logger {
+    Logger::root(slog::Discard, slog::o!())
+}
+
+/**
+ * Start an OS image archive with the least metadata we accept, a fixed
+ * timestamp, and the default compression.
+ */
+pub fn os_archive(p: &Path) -> Archive {
+    let md: BTreeMap<&str, &str> = [("v", "1"), ("t", "os")].into();
+    Archive::new(p, md, 0, Default::default()).unwrap()
+}
+
+/**
+ * Write a shell script that tests can run in place of a real program.
+ */
+pub fn script(p: &Path, body: &str) {
+    std::fs::write(p, format!("#!/bin/sh\n{body}\n")).unwrap();
+    std::fs::set_permissions(p, std::fs::Permissions::from_mode(0o755))
+        .unwrap();
+}
+
+/**
+ * Stand in for some of the tools we run with shell scripts that record their
+ * arguments, one line per run, before doing whatever else the test needs.
+ */
+#[derive(Clone)]
+pub struct FakeTools {
+    dir: PathBuf,
+}
+
+impl FakeTools {
+    pub fn new(dir: &Path) -> FakeTools {
+        std::fs::create_dir_all(dir).unwrap();
+        FakeTools { dir: dir.to_path_buf() }
+    }
+
+    pub fn add(&self, tool: Tool, body: &str) {
+        let calls = self.dir.join("calls");
+        script(
+            &self.dir.join(tool.name()),
+            &format!(
+                "echo \"{} $*\" >>'{}'\n{body}",
+                tool.name(),
+                calls.display(),
+            ),
+        );
+    }
+
+    pub fn calls(&self) -> String {
+        std::fs::read_to_string(self.dir.join("calls")).unwrap_or_default()
+    }
+
+    /**
+     * Use these tools for the commands run by the rest of this test.
+     */
+    pub fn install(&self) {
+        ensure::set_runner(Rc::new(self.clone()));
+    }
+}
+
+impl CommandRunner for FakeTools {
+    fn path(&self, tool: Tool) -> String {
+        let p = self.dir.join(tool.name());
+        if p.exists() {
+            p.to_str().unwrap().to_string()
+        } else {
+            tool.configured_path()
+        }
+    }
+}
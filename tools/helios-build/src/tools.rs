@@ -0,0 +1,197 @@
+/*
+ * Copyright 2026 Oxide Computer Company
+ */
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+
+use crate::common::read_toml;
+use crate::ensure;
+
+/**
+ * The external programs that we run.  Each has a default path, which may be
+ * overridden in "config/tools.toml" or, with higher precedence, in the
+ * environment; e.g., HELIOS_BUILD_TOOL_PKGRECV=/opt/pkg/bin/pkgrecv.  Tests
+ * may also substitute stand-in scripts by installing an ensure::CommandRunner.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tool {
+    Bash,
+    Cargo,
+    Git,
+    Pfexec,
+    Pkg,
+    Pkgdepotd,
+    Pkgmerge,
+    Pkgrecv,
+    Pkgrepo,
+    Psrinfo,
+    Rustup,
+    Sh,
+    Svcs,
+    Zfs,
+    Zlogin,
+    Zoneadm,
+    Zonecfg,
+}
+
+pub const ALL: &[Tool] = &[
+    Tool::Bash,
+    Tool::Cargo,
+    Tool::Git,
+    Tool::Pfexec,
+    Tool::Pkg,
+    Tool::Pkgdepotd,
+    Tool::Pkgmerge,
+    Tool::Pkgrecv,
+    Tool::Pkgrepo,
+    Tool::Psrinfo,
+    Tool::Rustup,
+    Tool::Sh,
+    Tool::Svcs,
+    Tool::Zfs,
+    Tool::Zlogin,
+    Tool::Zoneadm,
+    Tool::Zonecfg,
+];
+
+impl Tool {
+    /**
+     * The name by which the tool is known in the configuration file.
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tool::Bash => "bash",
+            Tool::Cargo => "cargo",
+            Tool::Git => "git",
+            Tool::Pfexec => "pfexec",
+            Tool::Pkg => "pkg",
+            Tool::Pkgdepotd => "pkg.depotd",
+            Tool::Pkgmerge => "pkgmerge",
+            Tool::Pkgrecv => "pkgrecv",
+            Tool::Pkgrepo => "pkgrepo",
+            Tool::Psrinfo => "psrinfo",
+            Tool::Rustup => "rustup",
+            Tool::Sh => "sh",
+            Tool::Svcs => "svcs",
+            Tool::Zfs => "zfs",
+            Tool::Zlogin => "zlogin",
+            Tool::Zoneadm => "zoneadm",
+            Tool::Zonecfg => "zonecfg",
+        }
+    }
+
+    /*
+     * Programs that are expected to be installed somewhere in PATH, rather
+     * than shipped with the operating system, have a bare name here.
+     */
+    fn default_path(&self) -> &'static str {
+        match self {
+            Tool::Bash => "bash",
+            Tool::Cargo => "cargo",
+            Tool::Git => "git",
+            Tool::Pfexec => "pfexec",
+            Tool::Pkg => "/usr/bin/pkg",
+            Tool::Pkgdepotd => "/usr/lib/pkg.depotd",
+            Tool::Pkgmerge => "/usr/bin/pkgmerge",
+            Tool::Pkgrecv => "/usr/bin/pkgrecv",
+            Tool::Pkgrepo => "/usr/bin/pkgrepo",
+            Tool::Psrinfo => "/usr/sbin/psrinfo",
+            Tool::Rustup => "rustup",
+            Tool::Sh => "/sbin/sh",
+            Tool::Svcs => "/bin/svcs",
+            Tool::Zfs => "/sbin/zfs",
+            Tool::Zlogin => "/usr/sbin/zlogin",
+            Tool::Zoneadm => "/usr/sbin/zoneadm",
+            Tool::Zonecfg => "/usr/sbin/zonecfg",
+        }
+    }
+
+    /**
+     * The environment variable that overrides the path to this tool; e.g.,
+     * HELIOS_BUILD_TOOL_PKG_DEPOTD for "pkg.depotd".
+     */
+    pub fn env_var(&self) -> String {
+        let name: String = self
+            .name()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        format!("HELIOS_BUILD_TOOL_{}", name.to_ascii_uppercase())
+    }
+
+    /**
+     * The path to this tool, as chosen by the current command runner.
+     */
+    pub fn path(&self) -> String {
+        ensure::runner().path(*self)
+    }
+
+    /**
+     * The path to this tool from the environment, the configuration file, or
+     * the built-in default, in that order of precedence.
+     */
+    pub fn configured_path(&self) -> String {
+        if let Ok(p) = std::env::var(self.env_var()) {
+            if !p.is_empty() {
+                return p;
+            }
+        }
+
+        if let Some(p) = OVERRIDES.lock().unwrap().get(self.name()) {
+            return p.to_string();
+        }
+
+        self.default_path().to_string()
+    }
+
+    /**
+     * A command to run this tool.  It should be started with one of the
+     * functions in the ensure module, which go through the current command
+     * runner and record the execution in the report.
+     */
+    pub fn command(&self) -> Command {
+        Command::new(self.path())
+    }
+}
+
+/*
+ * Paths loaded from the configuration file, by tool name.
+ */
+static OVERRIDES: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ToolsConfig {
+    #[serde(default)]
+    tools: BTreeMap<String, String>,
+}
+
+/**
+ * Load tool path overrides from a configuration file; e.g.,
+ *
+ *      [tools]
+ *      git = "/opt/ooce/bin/git"
+ *      "pkg.depotd" = "/opt/pkg/lib/pkg.depotd"
+ */
+pub fn load<P: AsRef<Path>>(p: P) -> Result<()> {
+    let p = p.as_ref();
+    let c: ToolsConfig = read_toml(p)?;
+
+    let mut overrides = OVERRIDES.lock().unwrap();
+    for (name, path) in c.tools {
+        if !ALL.iter().any(|t| t.name() == name) {
+            bail!("unknown tool {name:?} in {}", p.display());
+        }
+        if path.is_empty() {
+            bail!("empty path for tool {name:?} in {}", p.display());
+        }
+        overrides.insert(name, path);
+    }
+
+    Ok(())
+}
@@ -1,22 +1,27 @@
-use std::process::Command;
 /*
  * Copyright 2024 Oxide Computer Company
  */
 
 use anyhow::{bail, Result};
 
+use crate::ensure;
+use crate::tools::Tool;
+
 pub fn dataset_exists(dataset: &str) -> Result<bool> {
     if dataset.contains('@') {
         bail!("no @ allowed here");
     }
 
-    let zfs = Command::new("/sbin/zfs")
-        .env_clear()
-        .arg("list")
-        .arg("-Ho")
-        .arg("name")
-        .arg(dataset)
-        .output()?;
+    let zfs = ensure::capture(
+        Tool::Zfs
+            .command()
+            .env_clear()
+            .arg("list")
+            .arg("-Ho")
+            .arg("name")
+            .arg(dataset),
+        ensure::Timeout::Short,
+    )?;
 
     if !zfs.status.success() {
         let errmsg = String::from_utf8_lossy(&zfs.stderr);
@@ -30,15 +35,18 @@ pub fn dataset_exists(dataset: &str) -> Result<bool> {
 }
 
 pub fn zfs_get(dataset: &str, n: &str) -> Result<String> {
-    let zfs = Command::new("/sbin/zfs")
-        .env_clear()
-        .arg("get")
-        .arg("-H")
-        .arg("-o")
-        .arg("value")
-        .arg(n)
-        .arg(dataset)
-        .output()?;
+    let zfs = ensure::capture(
+        Tool::Zfs
+            .command()
+            .env_clear()
+            .arg("get")
+            .arg("-H")
+            .arg("-o")
+            .arg("value")
+            .arg(n)
+            .arg(dataset),
+        ensure::Timeout::Short,
+    )?;
 
     if !zfs.status.success() {
         let errmsg = String::from_utf8_lossy(&zfs.stderr);